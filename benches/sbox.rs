@@ -0,0 +1,87 @@
+// Compares the S-box layer of the backends on a single block.
+// Run with: cargo +nightly bench --bench sbox
+use fhaes::fhaes::AES;
+use fhaes::fhaes_boolean::{AesBoolean, AesByte};
+use fhaes::fhaes_shortint::{AesNibble, AesShortint};
+use fhaes::fhaes_uint128::{encrypt_block_u128, round_keys_u128, AesUint128};
+use fhaes::utils::{byte_from_u8, key_expansion, nibbles_from_u8};
+use tfhe::{ConfigBuilder, generate_keys, set_server_key, FheUint8};
+use tfhe::prelude::*;
+use std::time::Instant;
+
+const RUNS: u32 = 3;
+
+fn bench_fheuint8(block: [u8; 16], expanded_key: &[u8; 176]) {
+    let config = ConfigBuilder::default().build();
+    let (client_key, server_key) = generate_keys(config);
+    rayon::broadcast(|_| set_server_key(server_key.clone()));
+    set_server_key(server_key.clone());
+    let fhe_aes = AES::new(expanded_key.each_ref().map(|x| FheUint8::encrypt(*x, &client_key))).unwrap();
+    let block_enc = block.map(|x| FheUint8::encrypt(x, &client_key));
+
+    let instant = Instant::now();
+    for _ in 0..RUNS {
+        fhe_aes.sub_bytes_fhe(&block_enc);
+    }
+    println!("FheUint8 lookup table sub_bytes_fhe: {} ms", instant.elapsed().as_millis()/u128::from(RUNS));
+}
+
+fn bench_boolean(block: [u8; 16], expanded_key: &[u8; 176]) {
+    let (client_key, server_key) = tfhe::boolean::gen_keys();
+    let fhe_key_aes = expanded_key.each_ref().map(|x| byte_from_u8(&client_key, *x));
+    let fhe_aes = AesBoolean::new(fhe_key_aes, server_key).unwrap();
+    let block_enc: Vec<AesByte> = block.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+
+    let instant = Instant::now();
+    for _ in 0..RUNS {
+        fhe_aes.sub_bytes_fhe(&block_enc);
+    }
+    println!("boolean circuit sub_bytes_fhe: {} ms", instant.elapsed().as_millis()/u128::from(RUNS));
+}
+
+fn bench_shortint(block: [u8; 16], expanded_key: &[u8; 176]) {
+    let (client_key, server_key) = tfhe::shortint::gen_keys(tfhe::shortint::parameters::PARAM_MESSAGE_4_CARRY_4_KS_PBS);
+    let fhe_key_aes = expanded_key.each_ref().map(|x| nibbles_from_u8(&client_key, *x));
+    let fhe_aes = AesShortint::new(fhe_key_aes, server_key).unwrap();
+    let block_enc: Vec<AesNibble> = block.iter().map(|x| nibbles_from_u8(&client_key, *x)).collect();
+
+    let instant = Instant::now();
+    for _ in 0..RUNS {
+        fhe_aes.sub_bytes_fhe(&block_enc);
+    }
+    println!("shortint nibble sub_bytes_fhe: {} ms", instant.elapsed().as_millis()/u128::from(RUNS));
+}
+
+fn bench_uint128(block: [u8; 16], expanded_key: &[u8; 176]) {
+    let config = ConfigBuilder::default().build();
+    let (client_key, server_key) = generate_keys(config);
+    rayon::broadcast(|_| set_server_key(server_key.clone()));
+    set_server_key(server_key.clone());
+    let fhe_aes = AesUint128::new(round_keys_u128(&client_key, expanded_key)).unwrap();
+    let block_enc = encrypt_block_u128(&client_key, &block);
+
+    let instant = Instant::now();
+    for _ in 0..RUNS {
+        fhe_aes.sub_bytes_fhe(&block_enc);
+    }
+    println!("FheUint128 sub_bytes_fhe (byte extraction included): {} ms", instant.elapsed().as_millis()/u128::from(RUNS));
+
+    let instant = Instant::now();
+    for _ in 0..RUNS {
+        let block = fhe_aes.shift_rows_fhe(&block_enc);
+        let block = fhe_aes.mix_columns_fhe(&block);
+        fhe_aes.add_round_key_fhe(&block, 1);
+    }
+    println!("FheUint128 shift_rows + mix_columns + add_round_key: {} ms", instant.elapsed().as_millis()/u128::from(RUNS));
+}
+
+fn main() {
+    // the clear round keys stay in the Zeroizing buffer, the benches only borrow them
+    let expanded_key = key_expansion(&[0u8; 16]);
+    let block = std::array::from_fn::<u8, 16, _>(|x| x.try_into().unwrap());
+
+    bench_fheuint8(block, &expanded_key);
+    bench_boolean(block, &expanded_key);
+    bench_shortint(block, &expanded_key);
+    bench_uint128(block, &expanded_key);
+}
@@ -0,0 +1,34 @@
+use clap::Parser;
+use fhaes::error::FhaesError;
+use fhaes::shard::serve_worker_with;
+use std::io::Write;
+use std::net::TcpListener;
+
+// Worker process for fhaes::shard::ShardCoordinator
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    // address to listen on, port 0 picks a free port
+    #[arg(short, long, default_value = "127.0.0.1:0")]
+    listen: String,
+
+    // print `shard <id>` to stdout when a shard starts, e.g. for tests that need to know
+    #[arg(long, default_value_t = false)]
+    announce_shards: bool
+}
+
+fn main() -> Result<(), FhaesError> {
+    let args = Args::parse();
+
+    let listener = TcpListener::bind(&args.listen)?;
+    // the coordinator reads the address from the first line of stdout
+    println!("{}", listener.local_addr()?);
+    std::io::stdout().flush()?;
+    let announce_shards = args.announce_shards;
+    serve_worker_with(listener, |shard_id| {
+        if announce_shards {
+            println!("shard {}", shard_id);
+            let _ = std::io::stdout().flush();
+        }
+    })
+}
@@ -0,0 +1,118 @@
+use rayon::prelude::*;
+use crate::cipher::AesRounds;
+use crate::circuit::{self, constant_byte, Gates, GenericAes};
+use crate::error::{check_counter, FhaesError};
+use crate::execution::{ExecutionConfig, Stage};
+use zeroize::Zeroize;
+
+// Lane j of every u64 holds the bit of block j, so one gate evaluates 64 blocks.
+pub const LANES: usize = 64;
+
+// u64 lanes, the gates are plain bitwise operations without branches or lookups
+pub struct BitslicedGates;
+
+impl Gates<u64> for BitslicedGates {
+    fn xor(&self, a: &u64, b: &u64) -> u64 { a ^ b }
+    fn and(&self, a: &u64, b: &u64) -> u64 { a & b }
+    fn not(&self, a: &u64) -> u64 { !a }
+    fn mux(&self, condition: &u64, then: &u64, otherwise: &u64) -> u64 { otherwise ^ (condition & (then ^ otherwise)) }
+    fn constant(&self, value: bool) -> u64 { 0u64.wrapping_sub(u64::from(value)) }
+}
+
+// up to 64 blocks into 16 bytes of 8 lanes each
+pub fn pack_blocks(blocks: &[[u8; 16]]) -> Vec<Vec<u64>> {
+    (0..16).map(|byte| {
+        (0..8).map(|bit| {
+            let mut lane = 0u64;
+            for (j, block) in blocks.iter().enumerate() {
+                lane |= u64::from((block[byte] >> bit) & 1) << j;
+            }
+            lane
+        }).collect()
+    }).collect()
+}
+
+// the first `num_blocks` blocks of packed lanes
+pub fn unpack_blocks(lanes: &[Vec<u64>], num_blocks: usize) -> Vec<[u8; 16]> {
+    (0..num_blocks).map(|j| {
+        std::array::from_fn(|byte| {
+            let mut result = 0u8;
+            for bit in 0..8 {
+                result |= u8::try_from((lanes[byte][bit] >> j) & 1).unwrap() << bit;
+            }
+            result
+        })
+    }).collect()
+}
+
+// Cleartext AES-128 that evaluates the gate lists of circuit.rs (the Boyar-Peralta
+// SBox, ShiftRows and MixColumns of GenericAes) on 64 blocks at once. The key
+// schedule runs on the same circuit (circuit::key_expansion), so nothing is indexed
+// by key or data and unlike ReferenceAes it is constant time, and its output is
+// exactly what the FHE circuit computes. The round key lanes are wiped on drop.
+pub struct BitslicedAes {
+    aes             : GenericAes<u64, BitslicedGates>,
+}
+
+impl BitslicedAes {
+
+    // initialise, the key schedule runs on the lanes as well
+    pub fn from_key(key: &[u8; 16]) -> Result<BitslicedAes, FhaesError> {
+        // every lane sees the same key
+        let mut key_lanes: Vec<Vec<u64>> = key.iter().map(|x| constant_byte(&BitslicedGates, *x)).collect();
+        let round_keys = circuit::key_expansion(&BitslicedGates, &key_lanes);
+        key_lanes.zeroize();
+        BitslicedAes::from_round_key_lanes(round_keys?)
+    }
+
+    //(note this is not the 128 bit key, its the key after the offline key_expansion step)
+    pub fn from_expanded_key(expanded_key: &[u8; 176]) -> Result<BitslicedAes, FhaesError> {
+        BitslicedAes::from_round_key_lanes(expanded_key.iter().map(|x| constant_byte(&BitslicedGates, *x)).collect())
+    }
+
+    fn from_round_key_lanes(round_keys: Vec<Vec<u64>>) -> Result<BitslicedAes, FhaesError> {
+        let mut aes = GenericAes::new(BitslicedGates, round_keys)?;
+        // a gate is a single instruction, only the batches of 64 blocks are worth a thread
+        aes.set_execution_config(ExecutionConfig::default().with_threshold(Stage::Bytes, usize::MAX));
+        Ok(BitslicedAes {
+            aes             : aes,
+        })
+    }
+
+    // the circuit itself, e.g. to count or trace its gates
+    pub fn circuit(&self) -> &GenericAes<u64, BitslicedGates> {
+        &self.aes
+    }
+
+    // up to 64 packed blocks, see pack_blocks
+    pub fn encrypt_lanes(&self, lanes: Vec<Vec<u64>>) -> Result<Vec<Vec<u64>>, FhaesError> {
+        self.aes.encrypt_block(lanes)
+    }
+
+    pub fn encrypt_blocks(&self, blocks: &[[u8; 16]]) -> Result<Vec<[u8; 16]>, FhaesError> {
+        let chunks = blocks.par_chunks(LANES).map(|chunk| {
+            let lanes = self.encrypt_lanes(pack_blocks(chunk))?;
+            Ok(unpack_blocks(&lanes, chunk.len()))
+        }).collect::<Result<Vec<_>, FhaesError>>()?;
+        Ok(chunks.concat())
+    }
+
+    // same counter layout as ReferenceAes::encrypt_ctr_mode
+    pub fn encrypt_ctr_mode(&self, blocks: Vec<[u8; 16]>, mut iv: [u8; 16]) -> Result<Vec<[u8; 16]>, FhaesError> {
+        check_counter(blocks.len())?;
+        let counters: Vec<[u8; 16]> = (0..blocks.len()).map(|i| {
+            iv[15] = u8::try_from(i&0xf).unwrap();
+            iv[14] = u8::try_from((i>>4)&0xf).unwrap();
+            iv
+        }).collect();
+        let keystream = self.encrypt_blocks(&counters)?;
+        let results = blocks.iter().zip(keystream.iter()).map(|(block, c_n)| std::array::from_fn(|j| block[j]^c_n[j])).collect();
+        Ok(results)
+    }
+}
+
+impl Drop for BitslicedAes {
+    fn drop(&mut self) {
+        self.aes.zeroize();
+    }
+}
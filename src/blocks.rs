@@ -0,0 +1,72 @@
+use rayon::prelude::*;
+use crate::cipher::HomomorphicBlockCipher;
+use crate::error::{check_length, FhaesError};
+
+// Conversions between the clear representations of a block and the encrypted
+// blocks of any backend. The encrypted helpers go through the client side of
+// HomomorphicBlockCipher, e.g. encrypt_u128::<AesBoolean>(&client_key, x) or
+// decrypt_blocks::<AES>(&client_key, &blocks).
+
+// a u128 is read big-endian, byte 0 of the block is its most significant byte
+pub fn block_from_u128(value: u128) -> [u8; 16] {
+    value.to_be_bytes()
+}
+
+pub fn u128_from_block(block: &[u8; 16]) -> u128 {
+    u128::from_be_bytes(*block)
+}
+
+// 32 hex characters
+pub fn block_from_hex(input: &str) -> Result<[u8; 16], FhaesError> {
+    let mut block = [0u8; 16];
+    hex::decode_to_slice(input, &mut block)?;
+    Ok(block)
+}
+
+pub fn hex_from_block(block: &[u8; 16]) -> String {
+    hex::encode(block)
+}
+
+// the length has to be a multiple of 16, a trailing partial block is reported
+// against the length padded to the next whole block
+pub fn blocks_from_bytes(bytes: &[u8]) -> Result<Vec<[u8; 16]>, FhaesError> {
+    check_length("bytes (a multiple of 16)", bytes.len().next_multiple_of(16), bytes.len())?;
+    Ok(bytes.chunks(16).map(|x| x.try_into().unwrap()).collect())
+}
+
+pub fn bytes_from_blocks(blocks: &[[u8; 16]]) -> Vec<u8> {
+    blocks.concat()
+}
+
+pub fn encrypt_blocks<C: HomomorphicBlockCipher>(client_key: &C::ClientKey, blocks: &[[u8; 16]]) -> Vec<C::Block> {
+    blocks.par_iter().map(|x| C::encrypt_input(client_key, x)).collect()
+}
+
+pub fn decrypt_blocks<C: HomomorphicBlockCipher>(client_key: &C::ClientKey, blocks: &[C::Block]) -> Vec<[u8; 16]> {
+    blocks.par_iter().map(|x| C::decrypt_output(client_key, x)).collect()
+}
+
+pub fn encrypt_u128<C: HomomorphicBlockCipher>(client_key: &C::ClientKey, value: u128) -> C::Block {
+    C::encrypt_input(client_key, &block_from_u128(value))
+}
+
+pub fn decrypt_u128<C: HomomorphicBlockCipher>(client_key: &C::ClientKey, block: &C::Block) -> u128 {
+    u128_from_block(&C::decrypt_output(client_key, block))
+}
+
+pub fn encrypt_hex<C: HomomorphicBlockCipher>(client_key: &C::ClientKey, input: &str) -> Result<C::Block, FhaesError> {
+    Ok(C::encrypt_input(client_key, &block_from_hex(input)?))
+}
+
+pub fn decrypt_hex<C: HomomorphicBlockCipher>(client_key: &C::ClientKey, block: &C::Block) -> String {
+    hex_from_block(&C::decrypt_output(client_key, block))
+}
+
+// a whole message, one encrypted block per 16 bytes
+pub fn encrypt_bytes<C: HomomorphicBlockCipher>(client_key: &C::ClientKey, bytes: &[u8]) -> Result<Vec<C::Block>, FhaesError> {
+    Ok(encrypt_blocks::<C>(client_key, &blocks_from_bytes(bytes)?))
+}
+
+pub fn decrypt_bytes<C: HomomorphicBlockCipher>(client_key: &C::ClientKey, blocks: &[C::Block]) -> Vec<u8> {
+    bytes_from_blocks(&decrypt_blocks::<C>(client_key, blocks))
+}
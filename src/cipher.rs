@@ -0,0 +1,150 @@
+use std::ops::Range;
+use crate::error::{check_counter, check_length, FhaesError};
+use crate::execution::{ExecutionConfig, Stage};
+use crate::progress::Monitor;
+
+// bytes 14 and 15 of counter block i, the low nibble of the counter in byte 15
+// and the next one in byte 14, the layout of every ctr mode of this crate
+pub fn counter_bytes(i: usize) -> [u8; 2] {
+    [u8::try_from((i>>4)&0xf).unwrap(), u8::try_from(i&0xf).unwrap()]
+}
+
+// bytes 10..14 of the iv are xored with these in segment `segment` of a CTR
+// stream, which moves on to a new iv every MAX_CTR_BLOCKS blocks. Segment 0 is
+// the iv itself and the segments of one iv never share a counter block.
+pub fn segment_bytes(segment: u32) -> [u8; 4] {
+    segment.to_be_bytes()
+}
+
+// the iv of `segment` in the clear, see segment_bytes
+pub fn segment_iv_bytes(iv: &[u8; 16], segment: u32) -> [u8; 16] {
+    let mut block = *iv;
+    for (k, byte) in segment_bytes(segment).into_iter().enumerate() {
+        block[10+k] ^= byte;
+    }
+    block
+}
+
+// The server side of a backend. A backend only provides its round primitives
+// (the round keys, one round without AddRoundKey, the xor of two blocks), the
+// round loop with its monitor and the modes below are written once on top of
+// them and run on the pool of the backend's ExecutionConfig.
+pub trait AesRounds: Sync {
+    type Block: Clone + Send + Sync;
+    // whatever the round loop passes from one round to the next, an index into
+    // resident round keys or the key itself when it is derived on the fly
+    type RoundKey: Send + Sync;
+
+    fn execution_config(&self) -> &ExecutionConfig;
+
+    // reports the rounds of every mode and stops between rounds once its token
+    // is cancelled, the mode then returns FhaesError::Cancelled
+    fn monitor(&self) -> &Monitor;
+
+    // errors on blocks of the wrong shape and on operands the server key cannot use
+    fn check_block(&self, what: &'static str, block: &Self::Block) -> Result<(), FhaesError>;
+
+    // the iv with bytes 14 and 15 replaced by the public counter i, see counter_bytes
+    fn counter_block(&self, iv: &Self::Block, i: usize) -> Self::Block;
+
+    // the iv with segment_bytes(segment) xored into bytes 10..14
+    fn segment_iv(&self, iv: &Self::Block, segment: u32) -> Self::Block;
+
+    fn xor_blocks(&self, a: &Self::Block, b: &Self::Block) -> Self::Block;
+
+    // the key of round_no given the one of the previous round (None for round 0)
+    fn round_key(&self, round_no: usize, previous: Option<&Self::RoundKey>) -> Result<Self::RoundKey, FhaesError>;
+
+    fn add_round_key(&self, block: &Self::Block, round_key: &Self::RoundKey) -> Self::Block;
+
+    // SubBytes, ShiftRows and, unless it is the last round, MixColumns
+    fn round(&self, block: &Self::Block, last: bool) -> Self::Block;
+
+    // runs `rounds` on a state that already went through the previous ones, round_key
+    // is the key of the round before the first, returns the state and the last key used.
+    // The monitor is checked before and told after each round.
+    fn encrypt_rounds(&self, mut block: Self::Block, rounds: Range<usize>, mut round_key: Self::RoundKey) -> Result<(Self::Block, Self::RoundKey), FhaesError> {
+        for round_no in rounds {
+            self.monitor().check()?;
+            // the key schedule runs alongside the round when keys are derived on the fly
+            let (next_key, next_block) = rayon::join(
+                || self.round_key(round_no, Some(&round_key)),
+                || self.round(&block, round_no == 10));
+            round_key = next_key?;
+            block = self.add_round_key(&next_block, &round_key);
+            self.monitor().round_done();
+        }
+        Ok((block, round_key))
+    }
+
+    // the 10 rounds of a block that was already checked, the caller announces them to the monitor
+    fn encrypt_block_unchecked(&self, block: &Self::Block) -> Result<Self::Block, FhaesError> {
+        let round_key = self.round_key(0, None)?;
+        let block = self.add_round_key(block, &round_key);
+        Ok(self.encrypt_rounds(block, 1..11, round_key)?.0)
+    }
+
+    // the blocks block(0)..block(num_blocks) of a batch, spread over the blocks stage
+    fn encrypt_each<F: Fn(usize) -> Self::Block + Sync + Send>(&self, num_blocks: usize, block: F) -> Result<Vec<Self::Block>, FhaesError> {
+        let _run = self.monitor().add_work(10*num_blocks);
+        let execution = self.execution_config();
+        execution.install(|| {
+            let results = execution.map(Stage::Blocks, num_blocks, |i| self.encrypt_block_unchecked(&block(i)));
+            results.into_iter().collect()
+        })
+    }
+
+    fn encrypt_block(&self, block: Self::Block) -> Result<Self::Block, FhaesError> {
+        self.check_block("bytes per block", &block)?;
+        let _run = self.monitor().add_work(10);
+        self.execution_config().install(|| self.encrypt_block_unchecked(&block))
+    }
+
+    fn encrypt_blocks(&self, blocks: Vec<Self::Block>) -> Result<Vec<Self::Block>, FhaesError> {
+        for block in blocks.iter() {
+            self.check_block("bytes per block", block)?;
+        }
+        self.encrypt_each(blocks.len(), |i| blocks[i].clone())
+    }
+
+    // keystream of the counter blocks in `range`, independent of the data, so
+    // `range` must end at MAX_CTR_BLOCKS at the latest
+    fn generate_keystream(&self, iv: &Self::Block, range: Range<usize>) -> Result<Vec<Self::Block>, FhaesError> {
+        self.check_block("iv bytes", iv)?;
+        check_counter(range.end)?;
+        self.encrypt_each(range.len(), |k| self.counter_block(iv, range.start + k))
+    }
+
+    // xors a precomputed keystream with the blocks, both must have the same number of blocks
+    fn apply_keystream(&self, blocks: &Vec<Self::Block>, keystream: &Vec<Self::Block>) -> Result<Vec<Self::Block>, FhaesError> {
+        check_length("keystream blocks", blocks.len(), keystream.len())?;
+        for block in blocks.iter().chain(keystream.iter()) {
+            self.check_block("bytes per block", block)?;
+        }
+        let execution = self.execution_config();
+        execution.install(|| {
+            let results = execution.map(Stage::Blocks, blocks.len(), |i| self.xor_blocks(&blocks[i], &keystream[i]));
+            Ok(results)
+        })
+    }
+
+    fn encrypt_ctr(&self, blocks: Vec<Self::Block>, iv: Self::Block) -> Result<Vec<Self::Block>, FhaesError> {
+        check_counter(blocks.len())?;
+        let keystream = self.generate_keystream(&iv, 0..blocks.len())?;
+        self.apply_keystream(&blocks, &keystream)
+    }
+}
+
+// The interface shared by all the backends, so that application code and benches
+// can be written once. A block is whatever the backend encrypts 16 bytes into and
+// a key the encrypted output of the offline key_expansion step. Constructing the
+// cipher stays backend specific since every backend needs a different server key.
+pub trait HomomorphicBlockCipher: AesRounds {
+    type Key;
+    type ClientKey: Sync;
+
+    // client side
+    fn encrypt_key(client_key: &Self::ClientKey, expanded_key: &[u8; 176]) -> Self::Key;
+    fn encrypt_input(client_key: &Self::ClientKey, block: &[u8; 16]) -> Self::Block;
+    fn decrypt_output(client_key: &Self::ClientKey, block: &Self::Block) -> [u8; 16];
+}
@@ -0,0 +1,457 @@
+use tfhe::boolean::prelude::{BinaryBooleanGates, Ciphertext, ServerKey};
+use tfhe::prelude::*;
+use tfhe::FheBool;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zeroize::Zeroize;
+use crate::cipher::{counter_bytes, segment_bytes, AesRounds};
+use crate::error::{check_length, FhaesError};
+use crate::execution::{ExecutionConfig, Stage};
+use crate::fhaes_boolean::AesBit;
+use crate::progress::Monitor;
+use crate::utils::{and_bit, mux_bit, not_bit, xnor_bit, xor_bit};
+
+// Gate primitives over a bit representation B. The SBox circuit and the linear layers
+// below are written once against this trait, a new bit type only has to provide the
+// gates to get all of them (and the modes of cipher::AesRounds through GenericAes).
+pub trait Gates<B: Clone + Send + Sync>: Sync {
+    fn xor(&self, a: &B, b: &B) -> B;
+    fn and(&self, a: &B, b: &B) -> B;
+    fn not(&self, a: &B) -> B;
+    // condition ? then : otherwise
+    fn mux(&self, condition: &B, then: &B, otherwise: &B) -> B;
+    fn constant(&self, value: bool) -> B;
+
+    fn xnor(&self, a: &B, b: &B) -> B {
+        self.not(&self.xor(a, b))
+    }
+}
+
+// plain bools, the cleartext reference
+pub struct ClearGates;
+
+impl Gates<bool> for ClearGates {
+    fn xor(&self, a: &bool, b: &bool) -> bool { a ^ b }
+    fn and(&self, a: &bool, b: &bool) -> bool { a & b }
+    fn not(&self, a: &bool) -> bool { !a }
+    fn mux(&self, condition: &bool, then: &bool, otherwise: &bool) -> bool { if *condition { *then } else { *otherwise } }
+    fn constant(&self, value: bool) -> bool { value }
+}
+
+// low level tfhe::boolean ciphertexts, every gate is a bootstrap
+impl Gates<Ciphertext> for ServerKey {
+    fn xor(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext { BinaryBooleanGates::xor(self, a, b) }
+    fn and(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext { BinaryBooleanGates::and(self, a, b) }
+    fn not(&self, a: &Ciphertext) -> Ciphertext { ServerKey::not(self, a) }
+    fn mux(&self, condition: &Ciphertext, then: &Ciphertext, otherwise: &Ciphertext) -> Ciphertext { ServerKey::mux(self, condition, then, otherwise) }
+    fn constant(&self, value: bool) -> Ciphertext { self.trivial_encrypt(value) }
+    fn xnor(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext { BinaryBooleanGates::xnor(self, a, b) }
+}
+
+// AesBit, gates with a clear operand are folded without a bootstrap
+impl Gates<AesBit> for ServerKey {
+    fn xor(&self, a: &AesBit, b: &AesBit) -> AesBit { xor_bit(self, a, b) }
+    fn and(&self, a: &AesBit, b: &AesBit) -> AesBit { and_bit(self, a, b) }
+    fn not(&self, a: &AesBit) -> AesBit { not_bit(self, a) }
+    fn mux(&self, condition: &AesBit, then: &AesBit, otherwise: &AesBit) -> AesBit { mux_bit(self, condition, then, otherwise) }
+    fn constant(&self, value: bool) -> AesBit { AesBit::Clear(value) }
+    fn xnor(&self, a: &AesBit, b: &AesBit) -> AesBit { xnor_bit(self, a, b) }
+}
+
+// high level FheBool, the server key has to be set with set_server_key
+pub struct FheBoolGates;
+
+impl Gates<FheBool> for FheBoolGates {
+    fn xor(&self, a: &FheBool, b: &FheBool) -> FheBool { a ^ b }
+    fn and(&self, a: &FheBool, b: &FheBool) -> FheBool { a & b }
+    fn not(&self, a: &FheBool) -> FheBool { !a }
+    fn mux(&self, condition: &FheBool, then: &FheBool, otherwise: &FheBool) -> FheBool {
+        otherwise ^ (condition & (then ^ otherwise))
+    }
+    fn constant(&self, value: bool) -> FheBool { FheBool::encrypt_trivial(value) }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GateCounts {
+    pub xor     : usize,
+    pub and     : usize,
+    pub not     : usize,
+    pub mux     : usize,
+}
+
+impl GateCounts {
+    pub fn total(&self) -> usize {
+        self.xor + self.and + self.not + self.mux
+    }
+}
+
+// Evaluates in the clear and counts every gate, e.g. to see what a change to the
+// circuit costs before running it under FHE. xnor counts as one xor and one not.
+#[derive(Default)]
+pub struct GateCounter {
+    xor     : AtomicUsize,
+    and     : AtomicUsize,
+    not     : AtomicUsize,
+    mux     : AtomicUsize,
+}
+
+impl GateCounter {
+    pub fn counts(&self) -> GateCounts {
+        GateCounts {
+            xor     : self.xor.load(Ordering::Relaxed),
+            and     : self.and.load(Ordering::Relaxed),
+            not     : self.not.load(Ordering::Relaxed),
+            mux     : self.mux.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in [&self.xor, &self.and, &self.not, &self.mux] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Gates<bool> for GateCounter {
+    fn xor(&self, a: &bool, b: &bool) -> bool {
+        self.xor.fetch_add(1, Ordering::Relaxed);
+        a ^ b
+    }
+    fn and(&self, a: &bool, b: &bool) -> bool {
+        self.and.fetch_add(1, Ordering::Relaxed);
+        a & b
+    }
+    fn not(&self, a: &bool) -> bool {
+        self.not.fetch_add(1, Ordering::Relaxed);
+        !a
+    }
+    fn mux(&self, condition: &bool, then: &bool, otherwise: &bool) -> bool {
+        self.mux.fetch_add(1, Ordering::Relaxed);
+        if *condition { *then } else { *otherwise }
+    }
+    fn constant(&self, value: bool) -> bool { value }
+}
+
+// A byte is 8 bits with the least significant bit first, a block 16 bytes in AES order.
+
+pub fn bits_from_u8(input: u8) -> Vec<bool> {
+    (0..8).map(|i| ((input >> i) & 1) == 1).collect()
+}
+
+pub fn u8_from_bits(bits: &[bool]) -> u8 {
+    let mut result = 0;
+    for i in 0..8 {
+        result += (1<<i)*u8::from(bits[i]);
+    }
+    result
+}
+
+pub fn constant_byte<B: Clone + Send + Sync, G: Gates<B>>(gates: &G, input: u8) -> Vec<B> {
+    (0..8).map(|i| gates.constant(((input >> i) & 1) == 1)).collect()
+}
+
+pub fn xor_byte<B: Clone + Send + Sync, G: Gates<B>>(gates: &G, a: &[B], b: &[B]) -> Vec<B> {
+    a.iter().zip(b.iter()).map(|(x, y)| gates.xor(x, y)).collect()
+}
+
+// multiplication by x in GF(2^8), the reduction by 0x1B is 3 xors with the top bit
+pub fn xtime<B: Clone + Send + Sync, G: Gates<B>>(gates: &G, byte: &[B]) -> Vec<B> {
+    let h = &byte[7];
+    vec![
+        h.clone(),
+        gates.xor(&byte[0], h),
+        byte[1].clone(),
+        gates.xor(&byte[2], h),
+        gates.xor(&byte[3], h),
+        byte[4].clone(),
+        byte[5].clone(),
+        byte[6].clone(),
+    ]
+}
+
+// Boyar-Peralta SBox circuit, 32 and gates (see the references in the README)
+pub fn sbox<B: Clone + Send + Sync, G: Gates<B>>(gates: &G, byte: &[B]) -> Vec<B> {
+    let u0 = &byte[7];
+    let u1 = &byte[6];
+    let u2 = &byte[5];
+    let u3 = &byte[4];
+    let u4 = &byte[3];
+    let u5 = &byte[2];
+    let u6 = &byte[1];
+    let u7 = &byte[0];
+    let t1 = gates.xor(u3, u5);
+    let t2 = gates.xor(u0, u6);
+    let t3 = gates.xor(u0, u3);
+    let t4 = gates.xor(u0, u5);
+    let t5 = gates.xor(u1, u2);
+    let t6 = gates.xor(&t5, u7);
+    let t7 = gates.xor(&t6, u3);
+    let t8 = gates.xor(&t2, &t1);
+    let t9 = gates.xor(&t6, u0);
+    let t10 = gates.xor(&t6, u6);
+    let t11 = gates.xor(&t10, &t4);
+    let t12 = gates.xor(u4, &t8);
+    let t13 = gates.xor(&t12, u5);
+    let t14 = gates.xor(&t12, u1);
+    let t15 = gates.xor(&t13, u7);
+    let t16 = gates.xor(&t13, &t5);
+    let t17 = gates.xor(&t14, &t3);
+    let t18 = gates.xor(u7, &t17);
+    let t19 = gates.xor(&t16, &t17);
+    let t20 = gates.xor(&t16, &t4);
+    let t21 = gates.xor(&t5, &t17);
+    let t22 = gates.xor(&t2, &t21);
+    let t23 = gates.xor(u0, &t21);
+    let t24 = gates.and(&t8, &t13);
+    let t25 = gates.and(&t11, &t15);
+    let t26 = gates.xor(&t25, &t24);
+    let t27 = gates.and(&t7, u7);
+    let t28 = gates.xor(&t27, &t24);
+    let t29 = gates.and(&t2, &t21);
+    let t30 = gates.and(&t10, &t6);
+    let t31 = gates.xor(&t30, &t29);
+    let t32 = gates.and(&t9, &t18);
+    let t33 = gates.xor(&t32, &t29);
+    let t34 = gates.and(&t3, &t17);
+    let t35 = gates.and(&t1, &t19);
+    let t36 = gates.xor(&t35, &t34);
+    let t37 = gates.and(&t4, &t16);
+    let t38 = gates.xor(&t37, &t34);
+    let t39 = gates.xor(&t26, &t14);
+    let t40 = gates.xor(&t28, &t38);
+    let t41 = gates.xor(&t31, &t36);
+    let t42 = gates.xor(&t33, &t38);
+    let t43 = gates.xor(&t39, &t36);
+    let t44 = gates.xor(&t40, &t20);
+    let t45 = gates.xor(&t41, &t22);
+    let t46 = gates.xor(&t42, &t23);
+    let t47 = gates.xor(&t43, &t44);
+    let t48 = gates.and(&t43, &t45);
+    let t49 = gates.xor(&t46, &t48);
+    let t50 = gates.and(&t47, &t49);
+    let t51 = gates.xor(&t50, &t44);
+    let t52 = gates.xor(&t45, &t46);
+    let t53 = gates.xor(&t44, &t48);
+    let t54 = gates.and(&t53, &t52);
+    let t55 = gates.xor(&t54, &t46);
+    let t56 = gates.xor(&t45, &t55);
+    let t57 = gates.xor(&t49, &t55);
+    let t58 = gates.and(&t46, &t57);
+    let t59 = gates.xor(&t58, &t56);
+    let t60 = gates.xor(&t49, &t58);
+    let t61 = gates.and(&t51, &t60);
+    let t62 = gates.xor(&t47, &t61);
+    let t63 = gates.xor(&t62, &t59);
+    let t64 = gates.xor(&t51, &t55);
+    let t65 = gates.xor(&t51, &t62);
+    let t66 = gates.xor(&t55, &t59);
+    let t67 = gates.xor(&t64, &t63);
+    let t68 = gates.and(&t66, &t13);
+    let t69 = gates.and(&t59, &t15);
+    let t70 = gates.and(&t55, u7);
+    let t71 = gates.and(&t65, &t21);
+    let t72 = gates.and(&t62, &t6);
+    let t73 = gates.and(&t51, &t18);
+    let t74 = gates.and(&t64, &t17);
+    let t75 = gates.and(&t67, &t19);
+    let t76 = gates.and(&t63, &t16);
+    let t77 = gates.and(&t66, &t8);
+    let t78 = gates.and(&t59, &t11);
+    let t79 = gates.and(&t55, &t7);
+    let t80 = gates.and(&t65, &t2);
+    let t81 = gates.and(&t62, &t10);
+    let t82 = gates.and(&t51, &t9);
+    let t83 = gates.and(&t64, &t3);
+    let t84 = gates.and(&t67, &t1);
+    let t85 = gates.and(&t63, &t4);
+    let t86 = gates.xor(&t83, &t84);
+    let t87 = gates.xor(&t78, &t86);
+    let t88 = gates.xor(&t77, &t87);
+    let t89 = gates.xor(&t68, &t70);
+    let t90 = gates.xor(&t69, &t68);
+    let t91 = gates.xor(&t71, &t72);
+    let t92 = gates.xor(&t80, &t89);
+    let t93 = gates.xor(&t75, &t91);
+    let t94 = gates.xor(&t76, &t92);
+    let t95 = gates.xor(&t93, &t94);
+    let t96 = gates.xor(&t91, &t90);
+    let t97 = gates.xor(&t71, &t73);
+    let t98 = gates.xor(&t81, &t86);
+    let t99 = gates.xor(&t89, &t97);
+    let s3 = gates.xor(&t88, &t96);
+    let t100 = gates.xor(&t74, &t93);
+    let t101 = gates.xor(&t82, &t95);
+    let t102 = gates.xor(&t98, &t99);
+    let s7 = gates.xnor(&t80, &t102);
+    let t103 = gates.xor(&t83, &t100);
+    let t104 = gates.xor(&t87, &t79);
+    let s0 = gates.xor(&t88, &t100);
+    let s6 = gates.xnor(&t95, &t102);
+    let s4 = gates.xor(&t99, &s3);
+    let s1 = gates.xnor(&s3, &t100);
+    let t105 = gates.xor(&t101, &t103);
+    let s2 = gates.xnor(&t105, &t85);
+    let s5 = gates.xor(&t104, &t101);
+
+    vec![s7, s6, s5, s4, s3, s2, s1, s0]
+}
+
+// `expected` bytes of 8 bits each, for any byte that is a list of bits
+pub fn check_bytes<B, T: AsRef<[B]>>(what: &'static str, expected: usize, bytes: &[T]) -> Result<(), FhaesError> {
+    check_length(what, expected, bytes.len())?;
+    for byte in bytes {
+        check_length("bits per byte", 8, byte.as_ref().len())?;
+    }
+    Ok(())
+}
+
+pub fn shift_rows<T: Clone>(block: &[T]) -> Vec<T> {
+    let mut result = block.to_vec();
+    for i in 0..4 {
+        result[1+4*i] = block[1+4*((i+1)%4)].clone();
+        result[2+4*i] = block[2+4*((i+2)%4)].clone();
+        result[3+4*i] = block[3+4*((i+3)%4)].clone();
+    }
+    result
+}
+
+// the 4 output bytes of a column, b_i ^ b_(i+1) ^ a_(i+1) ^ a_(i+2) ^ a_(i+3) with b = xtime(a)
+pub fn mix_column<B: Clone + Send + Sync, G: Gates<B>>(gates: &G, column: &[Vec<B>]) -> Vec<Vec<B>> {
+    let b: Vec<Vec<B>> = column.iter().map(|x| xtime(gates, x)).collect();
+    (0..4).map(|i| {
+        let result = xor_byte(gates, &b[i], &b[(i+1)%4]);
+        let result = xor_byte(gates, &result, &column[(i+1)%4]);
+        let result = xor_byte(gates, &result, &column[(i+2)%4]);
+        xor_byte(gates, &result, &column[(i+3)%4])
+    }).collect()
+}
+
+// The AES-128 key schedule, 16 key bytes in and the 176 round key bytes out.
+// SubWord runs the SBox circuit above, so the key never indexes a table.
+pub fn key_expansion<B: Clone + Send + Sync, G: Gates<B>>(gates: &G, key: &[Vec<B>]) -> Result<Vec<Vec<B>>, FhaesError> {
+    check_bytes("key bytes", 16, key)?;
+    let rcon = [1u8, 2u8, 4u8, 8u8, 16u8, 32u8, 64u8, 128u8, 27u8, 54u8];
+    let mut expanded_key = key.to_vec();
+    for i in 4..44 {
+        let previous = &expanded_key[4*(i-1)..4*i];
+        let word: Vec<Vec<B>> = if i%4 == 0 {
+            // RotWord, SubWord and the round constant
+            let mut word: Vec<Vec<B>> = (0..4).map(|k| sbox(gates, &previous[(k+1)%4])).collect();
+            word[0] = xor_byte(gates, &word[0], &constant_byte(gates, rcon[(i/4)-1]));
+            word
+        } else {
+            previous.to_vec()
+        };
+        for k in 0..4 {
+            let byte = xor_byte(gates, &expanded_key[4*(i-4)+k], &word[k]);
+            expanded_key.push(byte);
+        }
+    }
+    Ok(expanded_key)
+}
+
+// AES-128 over any Gates implementation
+pub struct GenericAes<B: Clone + Send + Sync, G: Gates<B>> {
+    gates           : G,
+    round_keys      : Vec<Vec<B>>,
+    execution       : ExecutionConfig,
+    monitor         : Monitor,
+}
+
+impl<B: Clone + Send + Sync, G: Gates<B>> GenericAes<B, G> {
+
+    // initialise
+    //(note this is not the 128 bit key, its the 176 bytes after the offline key_expansion step)
+    pub fn new(gates: G, round_keys: Vec<Vec<B>>) -> Result<GenericAes<B, G>, FhaesError> {
+        check_bytes("round key bytes", 176, &round_keys)?;
+        Ok(GenericAes {
+            gates           : gates,
+            round_keys      : round_keys,
+            execution       : ExecutionConfig::default(),
+            monitor         : Monitor::default(),
+        })
+    }
+
+    //set the thread pool and per stage parallelism, see ExecutionConfig
+    pub fn set_execution_config(&mut self, execution: ExecutionConfig) {
+        self.execution = execution;
+    }
+
+    //set the progress callback and cancellation token, see Monitor (10 rounds per block)
+    pub fn set_monitor(&mut self, monitor: Monitor) {
+        self.monitor = monitor;
+    }
+
+    pub fn gates(&self) -> &G {
+        &self.gates
+    }
+
+    fn xor_bytes(&self, a: &[Vec<B>], b: &[Vec<B>]) -> Vec<Vec<B>> {
+        self.execution.map(Stage::Bytes, 16, |i| xor_byte(&self.gates, &a[i], &b[i]))
+    }
+
+    pub fn sub_bytes(&self, block: &Vec<Vec<B>>) -> Vec<Vec<B>> {
+        self.execution.map(Stage::Bytes, block.len(), |i| sbox(&self.gates, &block[i]))
+    }
+
+    pub fn mix_columns(&self, block: &Vec<Vec<B>>) -> Vec<Vec<B>> {
+        self.execution.map(Stage::Bytes, 4, |column| mix_column(&self.gates, &block[4*column..4*(column+1)])).concat()
+    }
+}
+
+impl<B: Clone + Send + Sync, G: Gates<B>> AesRounds for GenericAes<B, G> {
+    type Block = Vec<Vec<B>>;
+    type RoundKey = usize;
+
+    fn execution_config(&self) -> &ExecutionConfig {
+        &self.execution
+    }
+
+    fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    fn check_block(&self, what: &'static str, block: &Vec<Vec<B>>) -> Result<(), FhaesError> {
+        check_bytes(what, 16, block)
+    }
+
+    // the counters are public constants
+    fn counter_block(&self, iv: &Vec<Vec<B>>, i: usize) -> Vec<Vec<B>> {
+        let mut block = iv.clone();
+        let [counter_2, counter_1] = counter_bytes(i);
+        block[14] = constant_byte(&self.gates, counter_2);
+        block[15] = constant_byte(&self.gates, counter_1);
+        block
+    }
+
+    fn segment_iv(&self, iv: &Vec<Vec<B>>, segment: u32) -> Vec<Vec<B>> {
+        let mut block = iv.clone();
+        for (k, byte) in segment_bytes(segment).into_iter().enumerate() {
+            block[10+k] = xor_byte(&self.gates, &iv[10+k], &constant_byte(&self.gates, byte));
+        }
+        block
+    }
+
+    fn xor_blocks(&self, a: &Vec<Vec<B>>, b: &Vec<Vec<B>>) -> Vec<Vec<B>> {
+        self.xor_bytes(a, b)
+    }
+
+    fn round_key(&self, round_no: usize, _previous: Option<&usize>) -> Result<usize, FhaesError> {
+        Ok(round_no)
+    }
+
+    fn add_round_key(&self, block: &Vec<Vec<B>>, round_no: &usize) -> Vec<Vec<B>> {
+        self.xor_bytes(block, &self.round_keys[16*round_no..16*(round_no+1)])
+    }
+
+    fn round(&self, block: &Vec<Vec<B>>, last: bool) -> Vec<Vec<B>> {
+        let block = shift_rows(&self.sub_bytes(block));
+        if last { block } else { self.mix_columns(&block) }
+    }
+}
+
+// wipes the round keys, for bit types that hold clear key material
+impl<B: Clone + Send + Sync + Zeroize, G: Gates<B>> Zeroize for GenericAes<B, G> {
+    fn zeroize(&mut self) {
+        self.round_keys.zeroize();
+    }
+}
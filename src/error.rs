@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io;
+
+use crate::parameters::ParameterMismatch;
+
+// The counter of a CTR block is held in the low nibbles of bytes 14 and 15,
+// past this many blocks the keystream would repeat
+pub const MAX_CTR_BLOCKS: usize = 256;
+
+#[derive(Debug)]
+pub enum FhaesError {
+    // a block, key or counter list does not have the expected number of entries
+    InvalidLength { what: &'static str, expected: usize, found: usize },
+    // the requested blocks go past MAX_CTR_BLOCKS
+    CounterOverflow { blocks: usize },
+    // the server key has too little message or carry space for the lookup tables of a backend
+    UnsupportedParameters { what: &'static str },
+    // an operand was encrypted under another parameter set than the server key
+    ParameterMismatch(ParameterMismatch),
+    // a keystream, paged round key or shard message could not be decoded
+    Deserialization(bincode::Error),
+    // a hex encoded key or iv could not be parsed
+    InvalidHex(hex::FromHexError),
+    // the key could not be parsed, no details so that no part of it is printed
+    InvalidKey,
+    // a batch refers to a key handle that is not in the KeyRegistry
+    UnknownKey { handle: u64 },
+    // the CancellationToken of the Monitor was cancelled
+    Cancelled,
+    // a shard worker could not evaluate its shard, with the error it reported
+    Worker { message: String },
+    // tfhe refused an operation
+    Tfhe(tfhe::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for FhaesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FhaesError::InvalidLength { what, expected, found } => write!(f, "expected {} {}, found {}", expected, what, found),
+            FhaesError::CounterOverflow { blocks } => write!(f, "{} blocks requested but the counter only covers {}", blocks, MAX_CTR_BLOCKS),
+            FhaesError::UnsupportedParameters { what } => write!(f, "the server key parameters do not fit {}", what),
+            FhaesError::ParameterMismatch(e) => write!(f, "{}", e),
+            FhaesError::Deserialization(e) => write!(f, "deserialization failed: {}", e),
+            FhaesError::InvalidHex(e) => write!(f, "invalid hex: {}", e),
+            FhaesError::InvalidKey => write!(f, "invalid key, expected 32 hex characters"),
+            FhaesError::UnknownKey { handle } => write!(f, "no key registered under handle {}", handle),
+            FhaesError::Cancelled => write!(f, "cancelled"),
+            FhaesError::Worker { message } => write!(f, "worker failed: {}", message),
+            FhaesError::Tfhe(e) => write!(f, "tfhe: {}", e),
+            FhaesError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FhaesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FhaesError::ParameterMismatch(e) => Some(e),
+            FhaesError::Deserialization(e) => Some(e),
+            FhaesError::InvalidHex(e) => Some(e),
+            FhaesError::Tfhe(e) => Some(e),
+            FhaesError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<ParameterMismatch> for FhaesError {
+    fn from(e: ParameterMismatch) -> FhaesError {
+        FhaesError::ParameterMismatch(e)
+    }
+}
+
+impl From<bincode::Error> for FhaesError {
+    fn from(e: bincode::Error) -> FhaesError {
+        FhaesError::Deserialization(e)
+    }
+}
+
+impl From<hex::FromHexError> for FhaesError {
+    fn from(e: hex::FromHexError) -> FhaesError {
+        FhaesError::InvalidHex(e)
+    }
+}
+
+impl From<tfhe::Error> for FhaesError {
+    fn from(e: tfhe::Error) -> FhaesError {
+        FhaesError::Tfhe(e)
+    }
+}
+
+impl From<io::Error> for FhaesError {
+    fn from(e: io::Error) -> FhaesError {
+        FhaesError::Io(e)
+    }
+}
+
+pub fn check_length(what: &'static str, expected: usize, found: usize) -> Result<(), FhaesError> {
+    if expected != found {
+        return Err(FhaesError::InvalidLength { what: what, expected: expected, found: found });
+    }
+    Ok(())
+}
+
+// the end of a CTR range has to stay within the counter
+pub fn check_counter(end: usize) -> Result<(), FhaesError> {
+    if end > MAX_CTR_BLOCKS {
+        return Err(FhaesError::CounterOverflow { blocks: end });
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::sync::Arc;
+
+// The levels at which the backends can fan out work
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    // the 8 bits of a byte, e.g. the gates of a byte xor
+    Bits,
+    // the bytes (or columns) of a block, e.g. the S-boxes of SubBytes
+    Bytes,
+    // the blocks of a batch
+    Blocks
+}
+
+// Where and how finely the work of AesBoolean/AES is parallelised.
+// A stage only goes parallel when it has at least `threshold` items, so cheap
+// stages (a byte xor is 8 bootstraps at most) can stay on the current thread.
+// When a pool is given, every entry point runs inside it, which keeps the work
+// off the global rayon pool.
+#[derive(Clone)]
+pub struct ExecutionConfig {
+    pool                : Option<Arc<ThreadPool>>,
+    bits_threshold      : usize,
+    bytes_threshold     : usize,
+    blocks_threshold    : usize,
+}
+
+impl Default for ExecutionConfig {
+    fn default() -> ExecutionConfig {
+        ExecutionConfig {
+            pool                : None,
+            bits_threshold      : usize::MAX,
+            bytes_threshold     : 2,
+            blocks_threshold    : 2,
+        }
+    }
+}
+
+impl ExecutionConfig {
+    pub fn with_thread_pool(mut self, pool: Arc<ThreadPool>) -> ExecutionConfig {
+        self.pool = Some(pool);
+        self
+    }
+
+    // minimum number of items for `stage` to be split across threads,
+    // usize::MAX keeps the stage sequential and 0 or 1 always splits it
+    pub fn with_threshold(mut self, stage: Stage, threshold: usize) -> ExecutionConfig {
+        match stage {
+            Stage::Bits => self.bits_threshold = threshold,
+            Stage::Bytes => self.bytes_threshold = threshold,
+            Stage::Blocks => self.blocks_threshold = threshold,
+        }
+        self
+    }
+
+    pub fn threshold(&self, stage: Stage) -> usize {
+        match stage {
+            Stage::Bits => self.bits_threshold,
+            Stage::Bytes => self.bytes_threshold,
+            Stage::Blocks => self.blocks_threshold,
+        }
+    }
+
+    // runs `f` inside the configured pool, or on the current one
+    pub fn install<R: Send, F: FnOnce() -> R + Send>(&self, f: F) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(f),
+            None => f()
+        }
+    }
+
+    // (0..n).map(f), in parallel if n reaches the threshold of `stage`
+    pub fn map<R: Send, F: Fn(usize) -> R + Sync + Send>(&self, stage: Stage, n: usize, f: F) -> Vec<R> {
+        if n >= self.threshold(stage) {
+            (0..n).into_par_iter().map(f).collect()
+        } else {
+            (0..n).map(f).collect()
+        }
+    }
+}
@@ -1,41 +1,96 @@
 use tfhe::boolean::prelude::*;
-use rayon::prelude::*;
+use std::ops::Range;
 use std::time::Instant;
-use crate::utils::{byte_from_u8, mix_mux_gate, xor_aes_byte, xor_aes_byte_blocks};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::cell::RefCell;
+use std::io::{BufWriter, Seek, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::execution::{ExecutionConfig, Stage};
+use crate::error::{check_counter, check_length, FhaesError};
+use crate::parameters::{check_parameters, check_server_key};
+use crate::progress::Monitor;
+use crate::cipher::{counter_bytes, segment_bytes, AesRounds, HomomorphicBlockCipher};
+use crate::circuit::{self, check_bytes};
+use crate::utils::{byte_from_u8, key_expansion_round_fhe, xor_bit};
 
-#[derive(Clone)]
+// A bit that is either public or encrypted, gates with a public operand
+// are folded into a copy, a NOT or a constant instead of a bootstrap.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AesBit {
+    Clear(bool),
+    Encrypted(Ciphertext)
+}
+
+impl AesBit {
+    pub fn decrypt(self: &AesBit, client_key: &ClientKey) -> bool {
+        match self {
+            AesBit::Clear(b) => *b,
+            AesBit::Encrypted(c) => client_key.decrypt(c)
+        }
+    }
+
+    // turns a public bit into a trivial ciphertext for the gates that cannot be folded
+    pub fn to_ciphertext(self: &AesBit, server_key: &ServerKey) -> Ciphertext {
+        match self {
+            AesBit::Clear(b) => server_key.trivial_encrypt(*b),
+            AesBit::Encrypted(c) => c.clone()
+        }
+    }
+}
+
+impl From<Ciphertext> for AesBit {
+    fn from(c: Ciphertext) -> AesBit {
+        AesBit::Encrypted(c)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AesByte {
-    bits: Vec<Ciphertext>
+    bits: Vec<AesBit>
 }
 
 impl AesByte {
-    pub fn get_bits(self: &AesByte) -> Vec<Ciphertext> {
+    pub fn get_bits(self: &AesByte) -> Vec<AesBit> {
         self.bits.clone()
     }
 
-    pub fn top_bit(self: &AesByte) -> Ciphertext {
+    pub fn as_bits(self: &AesByte) -> &[AesBit] {
+        &self.bits
+    }
+
+    pub fn top_bit(self: &AesByte) -> AesBit {
         self.bits[7].clone()
     }
 
-    pub fn shift_left(self: &AesByte, f: &Ciphertext) -> AesByte {
+    pub fn shift_left(self: &AesByte) -> AesByte {
        let mut result_bits = self.bits.clone();
        for i in (1..8).rev() {
            result_bits[i]=result_bits[i-1].clone();
        }
-       result_bits[0] = f.clone();
+       result_bits[0] = AesBit::Clear(false);
        AesByte {
            bits: result_bits
        }
     }
 
-    pub fn new(input_bits: Vec<Ciphertext>) -> AesByte {
+    pub fn new(input_bits: Vec<AesBit>) -> AesByte {
         AesByte {
             bits: input_bits
         }
     }
 
+    // a public byte, e.g. a clear IV or plaintext
+    pub fn from_clear(input: u8) -> AesByte {
+        let bits = (0..8).map(|i| AesBit::Clear(((input >> i) & 1) == 1)).collect();
+        AesByte {
+            bits: bits
+        }
+    }
+
     pub fn decrypt(self: &AesByte, client_key: &ClientKey) -> u8 {
-        let result_bits: Vec<bool> = self.bits.iter().map(|x| client_key.decrypt(x)).collect();
+        let result_bits: Vec<bool> = self.bits.iter().map(|x| x.decrypt(client_key)).collect();
         let mut result = 0;
         for i in 0..8 {
             result += (1<<i)*u8::from(result_bits[i]);
@@ -43,162 +98,351 @@ impl AesByte {
         result
     }
 
+    // see circuit::sbox, gates with a clear input are folded
     pub fn sbox(self: &AesByte, server_key: &ServerKey) -> AesByte {
-        let U0 = self.bits[7].clone();
-        let U1 = self.bits[6].clone();
-        let U2 = self.bits[5].clone();
-        let U3 = self.bits[4].clone();
-        let U4 = self.bits[3].clone();
-        let U5 = self.bits[2].clone();
-        let U6 = self.bits[1].clone();
-        let U7 = self.bits[0].clone();
-        let t1 = server_key.xor(&U3, &U5);
-        let t2 = server_key.xor(&U0, &U6);
-        let t3 = server_key.xor(&U0, &U3);
-        let t4 = server_key.xor(&U0, &U5);
-        let t5 = server_key.xor(&U1, &U2);
-        let t6 = server_key.xor(&t5, &U7);
-        let t7 = server_key.xor(&t6, &U3);
-        let t8 = server_key.xor(&t2, &t1);
-        let t9 = server_key.xor(&t6, &U0);
-        let t10 = server_key.xor(&t6, &U6);
-        let t11 = server_key.xor(&t10, &t4);
-        let t12 = server_key.xor(&U4, &t8);
-        let t13 = server_key.xor(&t12, &U5);
-        let t14 = server_key.xor(&t12, &U1);
-        let t15 = server_key.xor(&t13, &U7);
-        let t16 = server_key.xor(&t13, &t5);
-        let t17 = server_key.xor(&t14, &t3);
-        let t18 = server_key.xor(&U7, &t17);
-        let t19 = server_key.xor(&t16, &t17);
-        let t20 = server_key.xor(&t16, &t4);
-        let t21 = server_key.xor(&t5, &t17);
-        let t22 = server_key.xor(&t2, &t21);
-        let t23 = server_key.xor(&U0, &t21);
-        let t24 = server_key.and(&t8, &t13);
-        let t25 = server_key.and(&t11, &t15);
-        let t26 = server_key.xor(&t25, &t24);
-        let t27 = server_key.and(&t7, &U7);
-        let t28 = server_key.xor(&t27, &t24);
-        let t29 = server_key.and(&t2, &t21);
-        let t30 = server_key.and(&t10, &t6);
-        let t31 = server_key.xor(&t30, &t29);
-        let t32 = server_key.and(&t9, &t18);
-        let t33 = server_key.xor(&t32, &t29);
-        let t34 = server_key.and(&t3, &t17);
-        let t35 = server_key.and(&t1, &t19);
-        let t36 = server_key.xor(&t35, &t34);
-        let t37 = server_key.and(&t4, &t16);
-        let t38 = server_key.xor(&t37, &t34);
-        let t39 = server_key.xor(&t26, &t14);
-        let t40 = server_key.xor(&t28, &t38);
-        let t41 = server_key.xor(&t31, &t36);
-        let t42 = server_key.xor(&t33, &t38);
-        let t43 = server_key.xor(&t39, &t36);
-        let t44 = server_key.xor(&t40, &t20);
-        let t45 = server_key.xor(&t41, &t22);
-        let t46 = server_key.xor(&t42, &t23);
-        let t47 = server_key.xor(&t43, &t44);
-        let t48 = server_key.and(&t43, &t45);
-        let t49 = server_key.xor(&t46, &t48);
-        let t50 = server_key.and(&t47, &t49);
-        let t51 = server_key.xor(&t50, &t44);
-        let t52 = server_key.xor(&t45, &t46);
-        let t53 = server_key.xor(&t44, &t48);
-        let t54 = server_key.and(&t53, &t52);
-        let t55 = server_key.xor(&t54, &t46);
-        let t56 = server_key.xor(&t45, &t55);
-        let t57 = server_key.xor(&t49, &t55);
-        let t58 = server_key.and(&t46, &t57);
-        let t59 = server_key.xor(&t58, &t56);
-        let t60 = server_key.xor(&t49, &t58);
-        let t61 = server_key.and(&t51, &t60);
-        let t62 = server_key.xor(&t47, &t61);
-        let t63 = server_key.xor(&t62, &t59);
-        let t64 = server_key.xor(&t51, &t55);
-        let t65 = server_key.xor(&t51, &t62);
-        let t66 = server_key.xor(&t55, &t59);
-        let t67 = server_key.xor(&t64, &t63);
-        let t68 = server_key.and(&t66, &t13);
-        let t69 = server_key.and(&t59, &t15);
-        let t70 = server_key.and(&t55, &U7);
-        let t71 = server_key.and(&t65, &t21);
-        let t72 = server_key.and(&t62, &t6);
-        let t73 = server_key.and(&t51, &t18);
-        let t74 = server_key.and(&t64, &t17);
-        let t75 = server_key.and(&t67, &t19);
-        let t76 = server_key.and(&t63, &t16);
-        let t77 = server_key.and(&t66, &t8);
-        let t78 = server_key.and(&t59, &t11);
-        let t79 = server_key.and(&t55, &t7);
-        let t80 = server_key.and(&t65, &t2);
-        let t81 = server_key.and(&t62, &t10);
-        let t82 = server_key.and(&t51, &t9);
-        let t83 = server_key.and(&t64, &t3);
-        let t84 = server_key.and(&t67, &t1);
-        let t85 = server_key.and(&t63, &t4);
-        let t86 = server_key.xor(&t83, &t84);
-        let t87 = server_key.xor(&t78, &t86);
-        let t88 = server_key.xor(&t77, &t87);
-        let t89 = server_key.xor(&t68, &t70);
-        let t90 = server_key.xor(&t69, &t68);
-        let t91 = server_key.xor(&t71, &t72);
-        let t92 = server_key.xor(&t80, &t89);
-        let t93 = server_key.xor(&t75, &t91);
-        let t94 = server_key.xor(&t76, &t92);
-        let t95 = server_key.xor(&t93, &t94);
-        let t96 = server_key.xor(&t91, &t90);
-        let t97 = server_key.xor(&t71, &t73);
-        let t98 = server_key.xor(&t81, &t86);
-        let t99 = server_key.xor(&t89, &t97);
-        let S3 = server_key.xor(&t88, &t96);
-        let t100 = server_key.xor(&t74, &t93);
-        let t101 = server_key.xor(&t82, &t95);
-        let t102 = server_key.xor(&t98, &t99);
-        let S7 = server_key.xnor(&t80, &t102);
-        let t103 = server_key.xor(&t83, &t100);
-        let t104 = server_key.xor(&t87, &t79);
-        let S0 = server_key.xor(&t88, &t100);
-        let S6 = server_key.xnor(&t95, &t102);
-        let S4 = server_key.xor(&t99, &S3);
-        let S1 = server_key.xnor(&S3, &t100);
-        let t105 = server_key.xor(&t101, &t103);
-        let S2 = server_key.xnor(&t105, &t85);
-        let S5 = server_key.xor(&t104, &t101);
-
-        let mut result_bits = Vec::new();
-        result_bits.push(S7);
-        result_bits.push(S6);
-        result_bits.push(S5);
-        result_bits.push(S4);
-        result_bits.push(S3);
-        result_bits.push(S2);
-        result_bits.push(S1);
-        result_bits.push(S0);
-        AesByte{
-            bits: result_bits
+        AesByte::new(circuit::sbox(server_key, &self.bits))
+    }
+}
+
+impl AsRef<[AesBit]> for AesByte {
+    fn as_ref(&self) -> &[AesBit] {
+        &self.bits
+    }
+}
+
+// What a client uploads in place of an AesByte. Each bit is a seeded ciphertext,
+// only the body and the seed of the mask are sent and the server regenerates the mask.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompressedAesByte {
+    bits: Vec<CompressedCiphertext>
+}
+
+impl CompressedAesByte {
+    pub fn new(input_bits: Vec<CompressedCiphertext>) -> CompressedAesByte {
+        CompressedAesByte {
+            bits: input_bits
+        }
+    }
+
+    pub fn decompress(self: &CompressedAesByte) -> AesByte {
+        let bits = self.bits.iter().map(|x| AesBit::Encrypted(Ciphertext::from(x.clone()))).collect();
+        AesByte::new(bits)
+    }
+}
+
+// Round 1 work shared by all the counter blocks of a CTR batch, see
+// AesBoolean::ctr_prefix_cache
+pub struct CtrPrefixCache {
+    sub_bytes       : Vec<AesByte>,
+    tail_columns    : Vec<AesByte>,
+    counter_key     : Vec<AesByte>,
+    round_key_1     : Vec<AesByte>
+}
+
+// the id of the next PagedRoundKeys, what the per thread caches are keyed by
+static NEXT_PAGED_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // the round this thread read last and the PagedRoundKeys it came from, every
+    // block walks through the rounds in order so a thread mostly rereads it
+    static CURRENT_ROUND: RefCell<Option<(u64, usize, Vec<AesByte>)>> = const { RefCell::new(None) };
+}
+
+// positional reads leave no cursor behind, so threads read rounds concurrently
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += u64::try_from(n).unwrap();
+            }
+        }
+    }
+    Ok(())
+}
+
+// Round keys written once to a new file, one serialized record of 16 bytes per
+// round, and read back with positional reads when a round needs them (the OS
+// page cache decides what actually stays in memory). Each thread keeps the round
+// it read last. The file belongs to the PagedRoundKeys and is removed when it is dropped.
+pub struct PagedRoundKeys {
+    id              : u64,
+    path            : PathBuf,
+    // where every record starts, and the end of the last one
+    offsets         : Vec<u64>,
+    file            : File
+}
+
+impl PagedRoundKeys {
+
+    // a file name in the temp directory that no other run uses, create fails
+    // rather than overwrite a file that appeared there in the meantime
+    pub fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("fhaes_round_keys_{}_{:016x}.bin", std::process::id(), rand::random::<u64>()))
+    }
+
+    pub fn create(path: PathBuf, aes_key: &[AesByte]) -> Result<PagedRoundKeys, FhaesError> {
+        check_length("round key bytes", 176, aes_key.len())?;
+        let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        let offsets = match PagedRoundKeys::write_rounds(&mut file, aes_key) {
+            Ok(offsets) => offsets,
+            Err(e) => {
+                let _ = std::fs::remove_file(&path);
+                return Err(e);
+            }
+        };
+        Ok(PagedRoundKeys {
+            id              : NEXT_PAGED_ID.fetch_add(1, Ordering::Relaxed),
+            path            : path,
+            offsets         : offsets,
+            file            : file
+        })
+    }
+
+    // the offset of every round in the file and the end of the file
+    fn write_rounds(file: &mut File, aes_key: &[AesByte]) -> Result<Vec<u64>, FhaesError> {
+        let mut writer = BufWriter::new(file);
+        let mut offsets = Vec::new();
+        for round_no in 0..11 {
+            offsets.push(writer.stream_position()?);
+            bincode::serialize_into(&mut writer, &aes_key[16*round_no..16*(round_no+1)].to_vec())?;
         }
+        offsets.push(writer.stream_position()?);
+        writer.flush()?;
+        Ok(offsets)
+    }
+
+    pub fn read(&self, round_no: usize) -> Result<Vec<AesByte>, FhaesError> {
+        let rounds = self.offsets.len() - 1;
+        if round_no >= rounds {
+            return Err(FhaesError::InvalidLength { what: "rounds", expected: rounds, found: round_no + 1 });
+        }
+        let cached = CURRENT_ROUND.with(|current| match &*current.borrow() {
+            Some((id, round, round_key)) if (*id, *round) == (self.id, round_no) => Some(round_key.clone()),
+            _ => None
+        });
+        if let Some(round_key) = cached {
+            return Ok(round_key);
+        }
+        let mut record = vec![0u8; usize::try_from(self.offsets[round_no+1] - self.offsets[round_no]).unwrap()];
+        read_exact_at(&self.file, &mut record, self.offsets[round_no])?;
+        let round_key: Vec<AesByte> = bincode::deserialize(&record)?;
+        // a file changed behind our back
+        check_length("round key bytes", 16, round_key.len())?;
+        CURRENT_ROUND.with(|current| *current.borrow_mut() = Some((self.id, round_no, round_key.clone())));
+        Ok(round_key)
+    }
+}
+
+impl Drop for PagedRoundKeys {
+    fn drop(&mut self) {
+        // the caches of other threads are replaced by the next round they read
+        CURRENT_ROUND.with(|current| {
+            let mut current = current.borrow_mut();
+            if matches!(&*current, Some((id, _, _)) if *id == self.id) {
+                *current = None;
+            }
+        });
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Where the encrypted key schedule lives, trading memory for compute:
+// - Resident keeps all 176 bytes (1408 ciphertexts) in memory
+// - Streamed keeps only the 16 byte master key and runs the key schedule
+//   homomorphically while encrypting, 40 extra SBoxes per block
+// - Paged keeps the schedule in a file and reads one round at a time
+pub enum RoundKeys {
+    Resident(Vec<AesByte>),
+    Streamed(Vec<AesByte>),
+    Paged(PagedRoundKeys)
+}
+
+// a block of 16 bytes of 8 bits each, encrypted under `parameters`, operands
+// encrypted under another parameter set would silently decrypt to garbage
+pub fn check_block(parameters: &BooleanParameters, what: &'static str, block: &[AesByte]) -> Result<(), FhaesError> {
+    check_bytes(what, 16, block)?;
+    check_parameters(parameters, block)?;
+    Ok(())
+}
+
+// Sets up an AesBoolean from server side artifacts only: the server key and an
+// encrypted key schedule taken from wherever it was stored or received. Public
+// constants (the counters, the SBox affine constant, clear IV bytes) are folded
+// into the gates or trivially encrypted with the server key, so nothing besides
+// the key schedule has to come from the client.
+pub struct AesBooleanBuilder {
+    server_key      : ServerKey,
+    key_bytes       : Vec<AesByte>,
+    streamed        : bool,
+    paged_path      : Option<PathBuf>,
+    parameters      : BooleanParameters,
+    execution       : ExecutionConfig,
+    monitor         : Monitor,
+}
+
+impl AesBooleanBuilder {
+    pub fn new(server_key: ServerKey) -> AesBooleanBuilder {
+        AesBooleanBuilder {
+            server_key      : server_key,
+            key_bytes       : Vec::new(),
+            streamed        : false,
+            paged_path      : None,
+            parameters      : DEFAULT_PARAMETERS,
+            execution       : ExecutionConfig::default(),
+            monitor         : Monitor::default(),
+        }
+    }
+
+    // the 176 encrypted bytes after the offline key_expansion step, e.g. a
+    // deserialized file or the decompressed client upload
+    pub fn with_key_schedule<I: IntoIterator<Item = AesByte>>(mut self, key_schedule: I) -> AesBooleanBuilder {
+        self.key_bytes = key_schedule.into_iter().collect();
+        self.streamed = false;
+        self
+    }
+
+    // only the 16 encrypted bytes of the 128 bit key, see RoundKeys::Streamed
+    pub fn with_master_key<I: IntoIterator<Item = AesByte>>(mut self, master_key: I) -> AesBooleanBuilder {
+        self.key_bytes = master_key.into_iter().collect();
+        self.streamed = true;
+        self
+    }
+
+    // writes the key schedule to the new file `path` when building, see RoundKeys::Paged,
+    // e.g. PagedRoundKeys::temp_path() (has no effect with a master key, there is nothing to page)
+    pub fn with_paged_round_keys(mut self, path: PathBuf) -> AesBooleanBuilder {
+        self.paged_path = Some(path);
+        self
+    }
+
+    // the parameter set the server key was generated with, build checks the key against it
+    pub fn with_parameters(mut self, parameters: BooleanParameters) -> AesBooleanBuilder {
+        self.parameters = parameters;
+        self
+    }
+
+    pub fn with_execution_config(mut self, execution: ExecutionConfig) -> AesBooleanBuilder {
+        self.execution = execution;
+        self
+    }
+
+    // progress callback and cancellation token, see Monitor
+    pub fn with_monitor(mut self, monitor: Monitor) -> AesBooleanBuilder {
+        self.monitor = monitor;
+        self
+    }
+
+    // checks the server key was generated under the parameters and the key has
+    // 176 (16 when streamed) bytes of 8 bits encrypted under them
+    pub fn build(self) -> Result<AesBoolean, FhaesError> {
+        let server_key = check_server_key(&self.parameters, self.server_key)?;
+        if self.streamed {
+            check_bytes("master key bytes", 16, &self.key_bytes)?;
+        } else {
+            check_bytes("round key bytes", 176, &self.key_bytes)?;
+        }
+        check_parameters(&self.parameters, &self.key_bytes)?;
+        let round_keys = match (self.streamed, self.paged_path) {
+            (true, _) => RoundKeys::Streamed(self.key_bytes),
+            (false, Some(path)) => RoundKeys::Paged(PagedRoundKeys::create(path, &self.key_bytes)?),
+            (false, None) => RoundKeys::Resident(self.key_bytes)
+        };
+        Ok(AesBoolean {
+            round_keys      : round_keys,
+            server_key      : server_key,
+            parameters      : self.parameters,
+            execution       : self.execution,
+            monitor         : self.monitor,
+        })
     }
 }
 
 pub struct AesBoolean {
-    aes_key_fhe     : [AesByte; 176],
+    round_keys      : RoundKeys,
     server_key      : ServerKey,
-    true_bit        : Ciphertext,
-    false_bit       : Ciphertext
+    parameters      : BooleanParameters,
+    execution       : ExecutionConfig,
+    monitor         : Monitor,
 }
 
 impl AesBoolean {
 
-    // initialise
-    pub fn new(aes_key: [AesByte; 176], key: ServerKey, t: Ciphertext, f: Ciphertext) -> AesBoolean {
+    // server side setup with a validated key schedule, see AesBooleanBuilder
+    pub fn builder(server_key: ServerKey) -> AesBooleanBuilder {
+        AesBooleanBuilder::new(server_key)
+    }
+
+    // initialise, the key schedule is checked against DEFAULT_PARAMETERS
+    pub fn new(aes_key: [AesByte; 176], key: ServerKey) -> Result<AesBoolean, FhaesError> {
+        AesBoolean::builder(key).with_key_schedule(aes_key).build()
+    }
 
-        AesBoolean{
-            aes_key_fhe     : aes_key,
-            server_key      : key,
-            true_bit        : t,
-            false_bit       : f
+    // only the encrypted 128 bit key is held, round keys are derived on the fly
+    pub fn new_streamed(master_key: [AesByte; 16], key: ServerKey) -> Result<AesBoolean, FhaesError> {
+        AesBoolean::builder(key).with_master_key(master_key).build()
+    }
 
+    // the expanded key is written to `path` and dropped from memory
+    pub fn new_paged(aes_key: [AesByte; 176], path: PathBuf, key: ServerKey) -> Result<AesBoolean, FhaesError> {
+        AesBoolean::builder(key).with_key_schedule(aes_key).with_paged_round_keys(path).build()
+    }
+
+    // The constructors check the keys against tfhe::boolean::gen_keys (DEFAULT_PARAMETERS),
+    // keys of another parameter set (see ParameterSet::gen_keys) are checked by the builder
+    // with AesBooleanBuilder::with_parameters, or here after the fact.
+    // The server key and the key schedule held in memory are checked against the new parameters.
+    pub fn with_parameters(mut self, parameters: BooleanParameters) -> Result<AesBoolean, FhaesError> {
+        self.server_key = check_server_key(&parameters, self.server_key)?;
+        match &self.round_keys {
+            RoundKeys::Resident(keys) | RoundKeys::Streamed(keys) => check_parameters(&parameters, keys)?,
+            RoundKeys::Paged(paged) => check_parameters(&parameters, &paged.read(0)?)?
+        }
+        self.parameters = parameters;
+        Ok(self)
+    }
+
+    pub fn parameters(&self) -> BooleanParameters {
+        self.parameters
+    }
+
+    // thread pool and per stage parallelism, see ExecutionConfig
+    pub fn with_execution_config(mut self, execution: ExecutionConfig) -> AesBoolean {
+        self.execution = execution;
+        self
+    }
+
+    // reports the rounds of every mode and stops between rounds once its token
+    // is cancelled, the mode then returns FhaesError::Cancelled
+    // (callers of encrypt_counter_block_cached announce their rounds themselves, 9 per block)
+    pub fn with_monitor(mut self, monitor: Monitor) -> AesBoolean {
+        self.monitor = monitor;
+        self
+    }
+
+    // round key of round_no, for streamed keys this reruns the key schedule from
+    // the master key, prefer AesRounds::round_key when walking through the rounds
+    pub fn round_key_fhe(&self, round_no: usize) -> Result<Vec<AesByte>, FhaesError> {
+        if round_no > 10 {
+            return Err(FhaesError::InvalidLength { what: "rounds", expected: 11, found: round_no + 1 });
+        }
+        match &self.round_keys {
+            RoundKeys::Resident(keys) => Ok(keys[16*round_no..16*(round_no+1)].to_vec()),
+            RoundKeys::Streamed(master_key) => {
+                let mut round_key = master_key.clone();
+                for t in 1..=round_no {
+                    round_key = key_expansion_round_fhe(&self.server_key, &self.execution, &round_key, t);
+                }
+                Ok(round_key)
+            },
+            RoundKeys::Paged(paged) => paged.read(round_no)
         }
     }
 
@@ -206,86 +450,253 @@ impl AesBoolean {
         blocks: Vec<Vec<AesByte>>, 
         iv: Vec<AesByte>, 
         counters_1: Vec<AesByte>, 
-        counters_2: Vec<AesByte>) -> Vec<Vec<AesByte>> {
+        counters_2: Vec<AesByte>) -> Result<Vec<Vec<AesByte>>, FhaesError> {
         let num_blocks = blocks.len();
-        let results = (0..num_blocks).into_par_iter().map( |i| { 
-            let mut current_iv = iv.clone();
-            current_iv[15] = counters_1[i].clone();
-            current_iv[14] = counters_2[i].clone();
-            let c_n = self.encrypt_one_block_fhe(current_iv);
-            let r_n = xor_aes_byte_blocks(&self.server_key, &blocks[i], &c_n);
-            r_n
-        }).collect();
-        return results 
-    }
-
-    pub fn encrypt_one_block_fhe(&self, mut block: Vec<AesByte>) -> Vec<AesByte>{
-        block = self.add_round_key_fhe(&block, 0);
-        for t in 0..9{
-            block = self.sub_bytes_fhe(&block);
-            block = self.shift_rows_fhe(&block);
-            block = self.mix_columns_fhe(&block);
-            block = self.add_round_key_fhe(&block, t+1);
+        check_counter(num_blocks)?;
+        for block in blocks.iter() {
+            self.check_block("bytes per block", block)?;
         }
-        block = self.sub_bytes_fhe(&block);
-        block = self.shift_rows_fhe(&block);
-        block = self.add_round_key_fhe(&block, 10);
-        block
+        self.check_block("iv bytes", &iv)?;
+        check_bytes("counters_1", num_blocks, &counters_1)?;
+        check_bytes("counters_2", num_blocks, &counters_2)?;
+        check_parameters(&self.parameters, &counters_1)?;
+        check_parameters(&self.parameters, &counters_2)?;
+        let keystream = self.encrypt_counters_cached(&iv, num_blocks, |cache, i| {
+            self.encrypt_counter_block_cached(cache, &counters_1[i], &counters_2[i])
+        })?;
+        self.apply_keystream(&blocks, &keystream)
+    }
+
+    // The first `num_bytes` keystream bytes as one flat vector, the last block
+    // only computes the bytes it needs, see encrypt_rounds_partial_fhe
+    pub fn generate_keystream_bytes_fhe(&self, iv: &Vec<AesByte>, num_bytes: usize) -> Result<Vec<AesByte>, FhaesError> {
+        self.generate_keystream_range_fhe(iv, 0..num_bytes)
     }
 
-    pub fn add_round_key_fhe(&self, block: &Vec<AesByte>, round_no: usize) -> Vec<AesByte>{
-        let mut result = block.clone();
-        for i in 0..16{
-            let key_idx = round_no*16+i;
-            result[i] = xor_aes_byte(&self.server_key, &result[i], &self.aes_key_fhe[key_idx]);
+    // Bytes `range` of the keystream, e.g. to patch a region of a large object.
+    // Only the blocks that overlap the range are computed, starting at counter
+    // range.start / 16, and the blocks at both ends only evaluate the bytes inside it.
+    pub fn generate_keystream_range_fhe(&self, iv: &Vec<AesByte>, range: Range<usize>) -> Result<Vec<AesByte>, FhaesError> {
+        self.check_block("iv bytes", iv)?;
+        let first_block = range.start / 16;
+        let end_block = range.end.div_ceil(16);
+        check_counter(end_block)?;
+        if range.is_empty() {
+            return Ok(Vec::new())
         }
-        result
+        let results = self.encrypt_counters_cached(iv, end_block - first_block, |cache, k| {
+            let i = first_block + k;
+            let [counter_2, counter_1] = counter_bytes(i).map(AesByte::from_clear);
+            // the part of the range inside block i
+            let output = usize::max(range.start, 16*i) - 16*i..usize::min(range.end, 16*(i+1)) - 16*i;
+            self.encrypt_counter_partial_cached(cache, &counter_1, &counter_2, output)
+        })?;
+        Ok(results.concat())
+    }
+
+    // CTR over a message of any length, encrypted or clear bytes in one flat vector
+    pub fn encrypt_ctr_bytes_fhe(&self, message: &Vec<AesByte>, iv: &Vec<AesByte>) -> Result<Vec<AesByte>, FhaesError> {
+        self.encrypt_ctr_range_fhe(message, iv, 0)
+    }
+
+    // CTR of `message` as the bytes at `offset` of a longer stream, matches the
+    // same bytes of encrypt_ctr_bytes_fhe on the whole stream
+    pub fn encrypt_ctr_range_fhe(&self, message: &Vec<AesByte>, iv: &Vec<AesByte>, offset: usize) -> Result<Vec<AesByte>, FhaesError> {
+        check_bytes("message bytes", message.len(), message)?;
+        check_parameters(&self.parameters, message)?;
+        check_counter((offset + message.len()).div_ceil(16))?;
+        let keystream = self.generate_keystream_range_fhe(iv, offset..offset + message.len())?;
+        self.execution.install(|| {
+            let results = self.execution.map(Stage::Bytes, message.len(), |i| self.xor_bytes(&message[i], &keystream[i]));
+            Ok(results)
+        })
+    }
+
+    pub fn xor_bytes(&self, a: &AesByte, b: &AesByte) -> AesByte {
+        let (a_bits, b_bits) = (a.as_bits(), b.as_bits());
+        AesByte::new(self.execution.map(Stage::Bits, 8, |i| xor_bit(&self.server_key, &a_bits[i], &b_bits[i])))
+    }
+
+    // the counter blocks 0..num_blocks of a batch that shares `iv`, `block` encrypts
+    // one of them through the prefix cache of the batch (9 rounds per block)
+    fn encrypt_counters_cached<R, F>(&self, iv: &Vec<AesByte>, num_blocks: usize, block: F) -> Result<Vec<R>, FhaesError>
+    where R: Send, F: Fn(&CtrPrefixCache, usize) -> Result<R, FhaesError> + Sync + Send {
+        let _run = self.monitor.add_work(9*num_blocks);
+        self.execution.install(|| {
+            let cache = self.ctr_prefix_cache(iv)?;
+            let results = self.execution.map(Stage::Blocks, num_blocks, |i| block(&cache, i));
+            results.into_iter().collect()
+        })
+    }
+
+    // rounds first_round..=10 as AesRounds::encrypt_rounds but only the bytes `output`
+    // of the result, the last round has no MixColumns so the S-boxes that ShiftRows
+    // moves outside of them are dead and skipped
+    fn encrypt_rounds_partial_fhe(&self, block: Vec<AesByte>, first_round: usize, round_key: Vec<AesByte>, output: Range<usize>) -> Result<Vec<AesByte>, FhaesError> {
+        let (block, round_key) = self.encrypt_rounds(block, first_round..10, round_key)?;
+        self.monitor.check()?;
+        // byte j of the output is the S-box of byte (j + 4*(j%4)) % 16 after ShiftRows
+        let (next_key, sub_bytes) = rayon::join(
+            || self.round_key(10, Some(&round_key)),
+            || self.execution.map(Stage::Bytes, output.len(), |k| {
+                let j = output.start + k;
+                block[(j + 4*(j%4)) % 16].sbox(&self.server_key)
+            }));
+        let next_key = next_key?;
+        let results = self.execution.map(Stage::Bytes, output.len(), |k| self.xor_bytes(&sub_bytes[k], &next_key[output.start + k]));
+        self.monitor.round_done();
+        Ok(results)
+    }
+
+    // Computes the part of the first two rounds that only depends on the 14 IV bytes
+    // shared by every counter block: round 0 AddRoundKey and round 1 SubBytes of
+    // bytes 0..14, and columns 2 and 3 of round 1 which ShiftRows keeps away
+    // from the counter bytes 14 and 15.
+    pub fn ctr_prefix_cache(&self, iv: &Vec<AesByte>) -> Result<CtrPrefixCache, FhaesError> {
+        self.check_block("iv bytes", iv)?;
+        let round_key_0 = self.round_key(0, None)?;
+        let round_key_1 = self.round_key(1, Some(&round_key_0))?;
+        let mut sub_bytes: Vec<AesByte> = self.execution.map(Stage::Bytes, 14, |i| {
+            self.xor_bytes(&iv[i], &round_key_0[i]).sbox(&self.server_key)
+        });
+        // placeholders for the counter bytes, they never reach columns 2 and 3
+        sub_bytes.push(AesByte::from_clear(0));
+        sub_bytes.push(AesByte::from_clear(0));
+
+        let shifted = self.shift_rows_fhe(&sub_bytes);
+        let mixed = self.execution.map(Stage::Bytes, 2, |k| self.mix_column_fhe(&shifted, 2+k)).concat();
+        let tail_columns = self.execution.map(Stage::Bytes, 8, |k| self.xor_bytes(&mixed[k], &round_key_1[8+k]));
+        sub_bytes.truncate(14);
+        Ok(CtrPrefixCache {
+            sub_bytes: sub_bytes,
+            tail_columns: tail_columns,
+            counter_key: round_key_0[14..16].to_vec(),
+            round_key_1: round_key_1
+        })
+    }
+
+    // encrypts iv || counter_2 || counter_1 reusing the shared prefix of the batch
+    pub fn encrypt_counter_block_cached(&self, cache: &CtrPrefixCache, counter_1: &AesByte, counter_2: &AesByte) -> Result<Vec<AesByte>, FhaesError> {
+        self.encrypt_counter_partial_cached(cache, counter_1, counter_2, 0..16)
+    }
+
+    // the bytes `output` of encrypt_counter_block_cached
+    fn encrypt_counter_partial_cached(&self, cache: &CtrPrefixCache, counter_1: &AesByte, counter_2: &AesByte, output: Range<usize>) -> Result<Vec<AesByte>, FhaesError> {
+        let counters = [counter_2, counter_1];
+        let mut sub_bytes = cache.sub_bytes.clone();
+        sub_bytes.extend(self.execution.map(Stage::Bytes, 2, |k| {
+            self.xor_bytes(counters[k], &cache.counter_key[k]).sbox(&self.server_key)
+        }));
+
+        let shifted = self.shift_rows_fhe(&sub_bytes);
+        let mixed = self.execution.map(Stage::Bytes, 2, |column| self.mix_column_fhe(&shifted, column)).concat();
+        let mut block = self.execution.map(Stage::Bytes, 8, |k| self.xor_bytes(&mixed[k], &cache.round_key_1[k]));
+        block.extend(cache.tail_columns.iter().cloned());
+        self.encrypt_rounds_partial_fhe(block, 2, cache.round_key_1.clone(), output)
+    }
+
+    pub fn add_round_key_fhe(&self, block: &Vec<AesByte>, round_no: usize) -> Result<Vec<AesByte>, FhaesError> {
+        Ok(self.xor_blocks(block, &self.round_key_fhe(round_no)?))
     }
 
     pub fn sub_bytes_fhe(&self, block: &Vec<AesByte>) -> Vec<AesByte> {
-        //todo
-        //let result = &block.par_iter().map_init(||{} , |rng, x| x.sbox(&self.server_key));
-        let result = block.into_par_iter().map(|x| x.sbox(&self.server_key)).collect();
-        result
+        self.execution.map(Stage::Bytes, block.len(), |i| block[i].sbox(&self.server_key))
     }
 
     pub fn shift_rows_fhe(&self, block: &Vec<AesByte>) -> Vec<AesByte> {
-        let mut result = block.clone();
-        for i in 0..4 {
-            result[1+4*i] = block[1+4*((i+1)%4)].clone();
-            result[2+4*i] = block[2+4*((i+2)%4)].clone();
-            result[3+4*i] = block[3+4*((i+3)%4)].clone();
-        }
-        result
+        circuit::shift_rows(block)
     }
 
     pub fn mix_columns_fhe(&self, block: &Vec<AesByte>) -> Vec<AesByte> {
-        let mut result = block.clone();
-        let mut b = block.clone();
-        for i in 0..16{
-            let h = &block[i].top_bit();
-            b[i] = block[i].shift_left(&self.false_bit);
-            b[i] = xor_aes_byte(&self.server_key, &b[i], &mix_mux_gate(&self.server_key,&h,&self.true_bit,&self.false_bit));
-            // 0x1B
+        let result = self.execution.map(Stage::Bytes, 4, |column| self.mix_column_fhe(block, column)).concat();
+        result
+    }
+
+    // the 4 output bytes of a single column of MixColumns
+    pub fn mix_column_fhe(&self, block: &Vec<AesByte>, column: usize) -> Vec<AesByte> {
+        let bits: Vec<Vec<AesBit>> = block[4*column..4*(column+1)].iter().map(|x| x.get_bits()).collect();
+        circuit::mix_column(&self.server_key, &bits).into_iter().map(AesByte::new).collect()
+    }
+}
+
+impl AesRounds for AesBoolean {
+    type Block = Vec<AesByte>;
+    type RoundKey = Vec<AesByte>;
+
+    fn execution_config(&self) -> &ExecutionConfig {
+        &self.execution
+    }
+
+    fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    fn check_block(&self, what: &'static str, block: &Vec<AesByte>) -> Result<(), FhaesError> {
+        check_block(&self.parameters, what, block)
+    }
+
+    fn counter_block(&self, iv: &Vec<AesByte>, i: usize) -> Vec<AesByte> {
+        let mut block = iv.clone();
+        let [counter_2, counter_1] = counter_bytes(i);
+        block[14] = AesByte::from_clear(counter_2);
+        block[15] = AesByte::from_clear(counter_1);
+        block
+    }
+
+    fn segment_iv(&self, iv: &Vec<AesByte>, segment: u32) -> Vec<AesByte> {
+        let mut block = iv.clone();
+        for (k, byte) in segment_bytes(segment).into_iter().enumerate() {
+            block[10+k] = self.xor_bytes(&iv[10+k], &AesByte::from_clear(byte));
         }
-       
-        for column in 0..4 {
-            
-            //result[0+4*column] =  b[0+4*column].clone();
-            //result[0+4*column] =  xor_aes_byte(&self.server_key,&b[0+4*column],&block[3+4*column])
-            result[0+4*column] = xor_aes_byte(&self.server_key, &xor_aes_byte(&self.server_key, 
-                                &xor_aes_byte(&self.server_key,&b[0+4*column],&block[3+4*column]),&block[2+4*column]),
-                                &xor_aes_byte(&self.server_key, &b[1+4*column],&block[1+4*column]));
-            result[1+4*column] = xor_aes_byte(&self.server_key, &xor_aes_byte(&self.server_key, 
-                                &xor_aes_byte(&self.server_key,&b[1+4*column],&block[0+4*column]),&block[3+4*column]),
-                                &xor_aes_byte(&self.server_key, &b[2+4*column],&block[2+4*column]));
-            result[2+4*column] = xor_aes_byte(&self.server_key, &xor_aes_byte(&self.server_key, 
-                                &xor_aes_byte(&self.server_key,&b[2+4*column],&block[1+4*column]),&block[0+4*column]),
-                                &xor_aes_byte(&self.server_key, &b[3+4*column],&block[3+4*column]));
-            result[3+4*column] = xor_aes_byte(&self.server_key, &xor_aes_byte(&self.server_key, 
-                                &xor_aes_byte(&self.server_key,&b[3+4*column],&block[2+4*column]),&block[1+4*column]),
-                                &xor_aes_byte(&self.server_key, &b[0+4*column],&block[0+4*column]));
+        block
+    }
+
+    fn xor_blocks(&self, a: &Vec<AesByte>, b: &Vec<AesByte>) -> Vec<AesByte> {
+        self.execution.map(Stage::Bytes, 16, |i| self.xor_bytes(&a[i], &b[i]))
+    }
+
+    // streamed keys run one step of the key schedule on the previous key
+    fn round_key(&self, round_no: usize, previous: Option<&Vec<AesByte>>) -> Result<Vec<AesByte>, FhaesError> {
+        match (&self.round_keys, previous) {
+            (RoundKeys::Streamed(_), Some(previous)) => Ok(key_expansion_round_fhe(&self.server_key, &self.execution, previous, round_no)),
+            _ => self.round_key_fhe(round_no)
         }
-        result
+    }
+
+    fn add_round_key(&self, block: &Vec<AesByte>, round_key: &Vec<AesByte>) -> Vec<AesByte> {
+        self.xor_blocks(block, round_key)
+    }
+
+    fn round(&self, block: &Vec<AesByte>, last: bool) -> Vec<AesByte> {
+        let block = self.shift_rows_fhe(&self.sub_bytes_fhe(block));
+        if last { block } else { self.mix_columns_fhe(&block) }
+    }
+
+    // the counter blocks share the prefix cache of the iv, 9 rounds per block
+    // (the counters follow counter_bytes and are public)
+    fn generate_keystream(&self, iv: &Vec<AesByte>, range: Range<usize>) -> Result<Vec<Vec<AesByte>>, FhaesError> {
+        self.check_block("iv bytes", iv)?;
+        check_counter(range.end)?;
+        self.encrypt_counters_cached(iv, range.len(), |cache, k| {
+            let [counter_2, counter_1] = counter_bytes(range.start + k).map(AesByte::from_clear);
+            self.encrypt_counter_block_cached(cache, &counter_1, &counter_2)
+        })
+    }
+}
+
+impl HomomorphicBlockCipher for AesBoolean {
+    type Key = [AesByte; 176];
+    type ClientKey = ClientKey;
+
+    fn encrypt_key(client_key: &ClientKey, expanded_key: &[u8; 176]) -> [AesByte; 176] {
+        expanded_key.each_ref().map(|x| byte_from_u8(client_key, *x))
+    }
+
+    fn encrypt_input(client_key: &ClientKey, block: &[u8; 16]) -> Vec<AesByte> {
+        block.iter().map(|x| byte_from_u8(client_key, *x)).collect()
+    }
+
+    fn decrypt_output(client_key: &ClientKey, block: &Vec<AesByte>) -> [u8; 16] {
+        std::array::from_fn(|i| block[i].decrypt(client_key))
     }
 }
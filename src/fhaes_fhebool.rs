@@ -0,0 +1,184 @@
+use tfhe::prelude::*;
+use tfhe::{ClientKey, FheBool, FheUint8};
+use serde::{Deserialize, Serialize};
+use crate::cipher::{counter_bytes, segment_bytes, AesRounds, HomomorphicBlockCipher};
+use crate::circuit::{self, check_bytes, FheBoolGates, GenericAes};
+use crate::error::FhaesError;
+use crate::execution::{ExecutionConfig, Stage};
+use crate::progress::Monitor;
+
+// A byte as 8 high level FheBool, bits[0] is the least significant bit.
+// Uses the keys of tfhe::generate_keys, the server key has to be set with set_server_key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FheBoolByte {
+    bits: Vec<FheBool>
+}
+
+impl FheBoolByte {
+    pub fn new(input_bits: Vec<FheBool>) -> FheBoolByte {
+        FheBoolByte {
+            bits: input_bits
+        }
+    }
+
+    pub fn encrypt(client_key: &ClientKey, input: u8) -> FheBoolByte {
+        let bits = (0..8).map(|i| FheBool::encrypt(((input >> i) & 1) == 1, client_key)).collect();
+        FheBoolByte::new(bits)
+    }
+
+    pub fn encrypt_trivial(input: u8) -> FheBoolByte {
+        let bits = (0..8).map(|i| FheBool::encrypt_trivial(((input >> i) & 1) == 1)).collect();
+        FheBoolByte::new(bits)
+    }
+
+    pub fn decrypt(self: &FheBoolByte, client_key: &ClientKey) -> u8 {
+        let mut result = 0;
+        for i in 0..8 {
+            let bit: bool = self.bits[i].decrypt(client_key);
+            result += (1<<i)*u8::from(bit);
+        }
+        result
+    }
+
+    // splits an FheUint8 computed elsewhere into its bits
+    pub fn from_fhe_uint8(input: &FheUint8) -> FheBoolByte {
+        let bits = (0..8).map(|i| (input & (1u8 << i)).ne(0u8)).collect();
+        FheBoolByte::new(bits)
+    }
+
+    // packs the bits back, e.g. to keep computing on the AES output with FheUint8
+    pub fn to_fhe_uint8(self: &FheBoolByte) -> FheUint8 {
+        let mut result = FheUint8::encrypt_trivial(0u8);
+        for i in 0..8 {
+            let bit: FheUint8 = self.bits[i].clone().cast_into();
+            result = result | (bit << u8::try_from(i).unwrap());
+        }
+        result
+    }
+
+    pub fn as_bits(self: &FheBoolByte) -> &[FheBool] {
+        &self.bits
+    }
+
+    pub fn xor(self: &FheBoolByte, other: &FheBoolByte) -> FheBoolByte {
+        let bits = self.bits.iter().zip(other.bits.iter()).map(|(a, b)| a ^ b).collect();
+        FheBoolByte::new(bits)
+    }
+
+    pub fn xtime(self: &FheBoolByte) -> FheBoolByte {
+        FheBoolByte::new(circuit::xtime(&FheBoolGates, &self.bits))
+    }
+
+    // same circuit as AesByte::sbox, see circuit::sbox
+    pub fn sbox(self: &FheBoolByte) -> FheBoolByte {
+        FheBoolByte::new(circuit::sbox(&FheBoolGates, &self.bits))
+    }
+}
+
+impl AsRef<[FheBool]> for FheBoolByte {
+    fn as_ref(&self) -> &[FheBool] {
+        &self.bits
+    }
+}
+
+fn to_bits(bytes: &[FheBoolByte]) -> Vec<Vec<FheBool>> {
+    bytes.iter().map(|x| x.bits.clone()).collect()
+}
+
+fn from_bits(bytes: Vec<Vec<FheBool>>) -> Vec<FheBoolByte> {
+    bytes.into_iter().map(FheBoolByte::new).collect()
+}
+
+// The generic AES of circuit.rs on FheBool, with the bytes wrapped as FheBoolByte
+pub struct AesFheBool {
+    aes             : GenericAes<FheBool, FheBoolGates>,
+}
+
+impl AesFheBool {
+
+    // initialise
+    //(note this is not the 128 bit key, its the key after the offline key_expansion step)
+    pub fn new(aes_key: [FheBoolByte; 176]) -> Result<AesFheBool, FhaesError> {
+        Ok(AesFheBool {
+            aes             : GenericAes::new(FheBoolGates, to_bits(&aes_key))?,
+        })
+    }
+
+    //set the thread pool and per stage parallelism
+    //(the server key is thread local, call set_server_key on every thread of the pool first)
+    pub fn set_execution_config(&mut self, execution: ExecutionConfig) {
+        self.aes.set_execution_config(execution);
+    }
+
+    //set the progress callback and cancellation token, see Monitor (10 rounds per block)
+    pub fn set_monitor(&mut self, monitor: Monitor) {
+        self.aes.set_monitor(monitor);
+    }
+}
+
+// The rounds of the generic AES, the bytes are unwrapped for the circuit
+impl AesRounds for AesFheBool {
+    type Block = Vec<FheBoolByte>;
+    type RoundKey = usize;
+
+    fn execution_config(&self) -> &ExecutionConfig {
+        self.aes.execution_config()
+    }
+
+    fn monitor(&self) -> &Monitor {
+        self.aes.monitor()
+    }
+
+    fn check_block(&self, what: &'static str, block: &Vec<FheBoolByte>) -> Result<(), FhaesError> {
+        check_bytes(what, 16, block)
+    }
+
+    fn counter_block(&self, iv: &Vec<FheBoolByte>, i: usize) -> Vec<FheBoolByte> {
+        let mut block = iv.clone();
+        let [counter_2, counter_1] = counter_bytes(i);
+        block[14] = FheBoolByte::encrypt_trivial(counter_2);
+        block[15] = FheBoolByte::encrypt_trivial(counter_1);
+        block
+    }
+
+    fn segment_iv(&self, iv: &Vec<FheBoolByte>, segment: u32) -> Vec<FheBoolByte> {
+        let mut block = iv.clone();
+        for (k, byte) in segment_bytes(segment).into_iter().enumerate() {
+            block[10+k] = iv[10+k].xor(&FheBoolByte::encrypt_trivial(byte));
+        }
+        block
+    }
+
+    fn xor_blocks(&self, a: &Vec<FheBoolByte>, b: &Vec<FheBoolByte>) -> Vec<FheBoolByte> {
+        self.aes.execution_config().map(Stage::Bytes, 16, |i| a[i].xor(&b[i]))
+    }
+
+    fn round_key(&self, round_no: usize, previous: Option<&usize>) -> Result<usize, FhaesError> {
+        self.aes.round_key(round_no, previous)
+    }
+
+    fn add_round_key(&self, block: &Vec<FheBoolByte>, round_no: &usize) -> Vec<FheBoolByte> {
+        from_bits(self.aes.add_round_key(&to_bits(block), round_no))
+    }
+
+    fn round(&self, block: &Vec<FheBoolByte>, last: bool) -> Vec<FheBoolByte> {
+        from_bits(self.aes.round(&to_bits(block), last))
+    }
+}
+
+impl HomomorphicBlockCipher for AesFheBool {
+    type Key = [FheBoolByte; 176];
+    type ClientKey = ClientKey;
+
+    fn encrypt_key(client_key: &ClientKey, expanded_key: &[u8; 176]) -> [FheBoolByte; 176] {
+        expanded_key.each_ref().map(|x| FheBoolByte::encrypt(client_key, *x))
+    }
+
+    fn encrypt_input(client_key: &ClientKey, block: &[u8; 16]) -> Vec<FheBoolByte> {
+        block.iter().map(|x| FheBoolByte::encrypt(client_key, *x)).collect()
+    }
+
+    fn decrypt_output(client_key: &ClientKey, block: &Vec<FheBoolByte>) -> [u8; 16] {
+        std::array::from_fn(|i| block[i].decrypt(client_key))
+    }
+}
@@ -0,0 +1,264 @@
+use tfhe::shortint::prelude::*;
+use tfhe::shortint::server_key::LookupTableOwned;
+use crate::cipher::{counter_bytes, segment_bytes, AesRounds, HomomorphicBlockCipher};
+use crate::error::{check_counter, check_length, FhaesError};
+use crate::execution::{ExecutionConfig, Stage};
+use crate::progress::Monitor;
+use crate::reference::xtime;
+use crate::utils::{nibbles_from_u8, sub_byte};
+
+// A byte held as two 4-bit shortint ciphertexts (high and low nibble).
+// The parameters must leave room for a second nibble in the carry space
+// (e.g. PARAM_MESSAGE_4_CARRY_4_KS_PBS) so that both fit in one lookup table.
+#[derive(Clone)]
+pub struct AesNibble {
+    hi: Ciphertext,
+    lo: Ciphertext
+}
+
+impl AesNibble {
+    pub fn new(hi: Ciphertext, lo: Ciphertext) -> AesNibble {
+        AesNibble {
+            hi: hi,
+            lo: lo
+        }
+    }
+
+    pub fn get_hi(self: &AesNibble) -> Ciphertext {
+        self.hi.clone()
+    }
+
+    pub fn get_lo(self: &AesNibble) -> Ciphertext {
+        self.lo.clone()
+    }
+
+    pub fn decrypt(self: &AesNibble, client_key: &ClientKey) -> u8 {
+        let hi = u8::try_from(client_key.decrypt(&self.hi)).unwrap();
+        let lo = u8::try_from(client_key.decrypt(&self.lo)).unwrap();
+        (hi << 4) | lo
+    }
+}
+
+// the byte (hi, lo) is packed into a single message as 16*hi + lo with linear
+// operations, so any function of a byte is one univariate lookup table
+fn packed_byte(packed: u64) -> u8 {
+    u8::try_from(packed & 0xff).unwrap()
+}
+
+pub struct AesShortint {
+    aes_key_fhe     : [AesNibble; 176],
+    server_key      : ServerKey,
+    xor_lut         : LookupTableOwned,
+    xtime_hi_lut    : LookupTableOwned,
+    xtime_lo_lut    : LookupTableOwned,
+    sbox_hi_lut     : LookupTableOwned,
+    sbox_lo_lut     : LookupTableOwned,
+    execution       : ExecutionConfig,
+    monitor         : Monitor,
+}
+
+impl AesShortint {
+
+    // initialise, fails if the parameters cannot hold two nibbles in one lookup table
+    pub fn new(aes_key: [AesNibble; 176], key: ServerKey) -> Result<AesShortint, FhaesError> {
+        if key.message_modulus.0 < 16 || key.carry_modulus.0 < 16 {
+            return Err(FhaesError::UnsupportedParameters { what: "a lookup table on two packed 4 bit nibbles" });
+        }
+        let xor_lut = key.generate_lookup_table(|x| (x >> 4) ^ (x & 0xf));
+        let xtime_hi_lut = key.generate_lookup_table(|x| u64::from(xtime(packed_byte(x)) >> 4));
+        let xtime_lo_lut = key.generate_lookup_table(|x| u64::from(xtime(packed_byte(x)) & 0xf));
+        let sbox_hi_lut = key.generate_lookup_table(|x| u64::from(sub_byte(packed_byte(x)) >> 4));
+        let sbox_lo_lut = key.generate_lookup_table(|x| u64::from(sub_byte(packed_byte(x)) & 0xf));
+
+        Ok(AesShortint{
+            aes_key_fhe     : aes_key,
+            server_key      : key,
+            xor_lut         : xor_lut,
+            xtime_hi_lut    : xtime_hi_lut,
+            xtime_lo_lut    : xtime_lo_lut,
+            sbox_hi_lut     : sbox_hi_lut,
+            sbox_lo_lut     : sbox_lo_lut,
+            execution       : ExecutionConfig::default(),
+            monitor         : Monitor::default(),
+        })
+    }
+
+    //set the thread pool and per stage parallelism, the two nibbles of a byte are the bits stage
+    pub fn set_execution_config(&mut self, execution: ExecutionConfig) {
+        self.execution = execution;
+    }
+
+    //set the progress callback and cancellation token, see Monitor (10 rounds per block)
+    pub fn set_monitor(&mut self, monitor: Monitor) {
+        self.monitor = monitor;
+    }
+
+    // CTR with counter bytes that are themselves encrypted, counters_1[i] is byte 15
+    // and counters_2[i] byte 14 of block i
+    pub fn encrypt_ctr_mode_fhe(&self,
+        blocks: Vec<Vec<AesNibble>>,
+        iv: Vec<AesNibble>,
+        counters_1: Vec<AesNibble>,
+        counters_2: Vec<AesNibble>) -> Result<Vec<Vec<AesNibble>>, FhaesError> {
+        let num_blocks = blocks.len();
+        check_counter(num_blocks)?;
+        self.check_block("iv bytes", &iv)?;
+        check_length("counters_1", num_blocks, counters_1.len())?;
+        check_length("counters_2", num_blocks, counters_2.len())?;
+        for block in blocks.iter() {
+            self.check_block("bytes per block", block)?;
+        }
+        let keystream = self.encrypt_each(num_blocks, |i| {
+            let mut counter_block = iv.clone();
+            counter_block[15] = counters_1[i].clone();
+            counter_block[14] = counters_2[i].clone();
+            counter_block
+        })?;
+        self.apply_keystream(&blocks, &keystream)
+    }
+
+    fn trivial_nibbles(&self, input: u8) -> AesNibble {
+        let hi = self.server_key.create_trivial(u64::from(input >> 4));
+        let lo = self.server_key.create_trivial(u64::from(input & 0xf));
+        AesNibble::new(hi, lo)
+    }
+
+    // 16*a + b only takes a scalar multiplication and an addition, the carry space
+    // checked in new holds it, and the lookup table leaves a clean nibble behind
+    fn lookup(&self, a: &Ciphertext, b: &Ciphertext, lut: &LookupTableOwned) -> Ciphertext {
+        let mut packed = self.server_key.unchecked_scalar_mul(a, 16);
+        self.server_key.unchecked_add_assign(&mut packed, b);
+        self.server_key.apply_lookup_table(&packed, lut)
+    }
+
+    // the lookups of the high (0) and the low (1) nibble, on the bits stage
+    fn nibbles<F: Fn(usize) -> Ciphertext + Sync + Send>(&self, f: F) -> AesNibble {
+        let mut nibbles = self.execution.map(Stage::Bits, 2, f);
+        let lo = nibbles.pop().unwrap();
+        let hi = nibbles.pop().unwrap();
+        AesNibble::new(hi, lo)
+    }
+
+    pub fn xor_nibbles(&self, a: &AesNibble, b: &AesNibble) -> AesNibble {
+        self.nibbles(|k| match k {
+            0 => self.lookup(&a.hi, &b.hi, &self.xor_lut),
+            _ => self.lookup(&a.lo, &b.lo, &self.xor_lut)
+        })
+    }
+
+    fn xor_bytes(&self, a: &[AesNibble], b: &[AesNibble]) -> Vec<AesNibble> {
+        self.execution.map(Stage::Bytes, a.len(), |i| self.xor_nibbles(&a[i], &b[i]))
+    }
+
+    pub fn add_round_key_fhe(&self, block: &Vec<AesNibble>, round_no: usize) -> Vec<AesNibble>{
+        self.xor_bytes(block, &self.aes_key_fhe[round_no*16..round_no*16+16])
+    }
+
+    // one lookup per output nibble, both on the packed input byte
+    pub fn sbox(&self, x: &AesNibble) -> AesNibble {
+        self.nibbles(|k| match k {
+            0 => self.lookup(&x.hi, &x.lo, &self.sbox_hi_lut),
+            _ => self.lookup(&x.hi, &x.lo, &self.sbox_lo_lut)
+        })
+    }
+
+    pub fn sub_bytes_fhe(&self, block: &Vec<AesNibble>) -> Vec<AesNibble> {
+        self.execution.map(Stage::Bytes, block.len(), |i| self.sbox(&block[i]))
+    }
+
+    pub fn shift_rows_fhe(&self, block: &Vec<AesNibble>) -> Vec<AesNibble> {
+        let mut result = block.clone();
+        for i in 0..4 {
+            result[1+4*i] = block[1+4*((i+1)%4)].clone();
+            result[2+4*i] = block[2+4*((i+2)%4)].clone();
+            result[3+4*i] = block[3+4*((i+3)%4)].clone();
+        }
+        result
+    }
+
+    pub fn mix_columns_fhe(&self, block: &Vec<AesNibble>) -> Vec<AesNibble> {
+        let b: Vec<AesNibble> = self.execution.map(Stage::Bytes, 16, |i| self.nibbles(|k| match k {
+            0 => self.lookup(&block[i].hi, &block[i].lo, &self.xtime_hi_lut),
+            _ => self.lookup(&block[i].hi, &block[i].lo, &self.xtime_lo_lut)
+        }));
+
+        let result = self.execution.map(Stage::Bytes, 16, |idx| {
+            let column = idx/4;
+            let row = idx%4;
+            let at = |r: usize| 4*column + (row+r)%4;
+            let mut acc = self.xor_nibbles(&b[at(0)], &block[at(3)]);
+            acc = self.xor_nibbles(&acc, &block[at(2)]);
+            acc = self.xor_nibbles(&acc, &b[at(1)]);
+            self.xor_nibbles(&acc, &block[at(1)])
+        });
+        result
+    }
+}
+
+impl AesRounds for AesShortint {
+    type Block = Vec<AesNibble>;
+    type RoundKey = usize;
+
+    fn execution_config(&self) -> &ExecutionConfig {
+        &self.execution
+    }
+
+    fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    fn check_block(&self, what: &'static str, block: &Vec<AesNibble>) -> Result<(), FhaesError> {
+        check_length(what, 16, block.len())
+    }
+
+    // the counters are public so trivial ciphertexts
+    fn counter_block(&self, iv: &Vec<AesNibble>, i: usize) -> Vec<AesNibble> {
+        let mut block = iv.clone();
+        let [counter_2, counter_1] = counter_bytes(i);
+        block[14] = self.trivial_nibbles(counter_2);
+        block[15] = self.trivial_nibbles(counter_1);
+        block
+    }
+
+    fn segment_iv(&self, iv: &Vec<AesNibble>, segment: u32) -> Vec<AesNibble> {
+        let mut block = iv.clone();
+        for (k, byte) in segment_bytes(segment).into_iter().enumerate() {
+            block[10+k] = self.xor_nibbles(&iv[10+k], &self.trivial_nibbles(byte));
+        }
+        block
+    }
+
+    fn xor_blocks(&self, a: &Vec<AesNibble>, b: &Vec<AesNibble>) -> Vec<AesNibble> {
+        self.xor_bytes(a, b)
+    }
+
+    fn round_key(&self, round_no: usize, _previous: Option<&usize>) -> Result<usize, FhaesError> {
+        Ok(round_no)
+    }
+
+    fn add_round_key(&self, block: &Vec<AesNibble>, round_no: &usize) -> Vec<AesNibble> {
+        self.add_round_key_fhe(block, *round_no)
+    }
+
+    fn round(&self, block: &Vec<AesNibble>, last: bool) -> Vec<AesNibble> {
+        let block = self.shift_rows_fhe(&self.sub_bytes_fhe(block));
+        if last { block } else { self.mix_columns_fhe(&block) }
+    }
+}
+
+impl HomomorphicBlockCipher for AesShortint {
+    type Key = [AesNibble; 176];
+    type ClientKey = ClientKey;
+
+    fn encrypt_key(client_key: &ClientKey, expanded_key: &[u8; 176]) -> [AesNibble; 176] {
+        expanded_key.each_ref().map(|x| nibbles_from_u8(client_key, *x))
+    }
+
+    fn encrypt_input(client_key: &ClientKey, block: &[u8; 16]) -> Vec<AesNibble> {
+        block.iter().map(|x| nibbles_from_u8(client_key, *x)).collect()
+    }
+
+    fn decrypt_output(client_key: &ClientKey, block: &Vec<AesNibble>) -> [u8; 16] {
+        std::array::from_fn(|i| block[i].decrypt(client_key))
+    }
+}
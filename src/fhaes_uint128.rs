@@ -0,0 +1,188 @@
+use tfhe::prelude::*;
+use tfhe::{ClientKey, FheUint128, FheUint8};
+use crate::cipher::{counter_bytes, segment_bytes, AesRounds, HomomorphicBlockCipher};
+use crate::error::FhaesError;
+use crate::execution::{ExecutionConfig, Stage};
+use crate::progress::Monitor;
+use crate::fhaes::sbox_lookup;
+
+// The whole AES state as a single FheUint128. Byte i of a block sits in bits
+// 8*i..8*i+8 (u128::from_le_bytes), so column c of the column major state is the
+// 32 bit lane 32*c..32*c+32 and row r of that column the byte at 8*r inside the lane.
+
+// bits of the rows first_row..last_row in every column
+fn row_mask(first_row: u32, last_row: u32) -> u128 {
+    let mut mask = 0u128;
+    for column in 0..4 {
+        for row in first_row..last_row {
+            mask |= 0xffu128 << (32*column + 8*row);
+        }
+    }
+    mask
+}
+
+// the lowest bit / all but the lowest bit of every byte
+const BYTE_LOW_BITS: u128 = 0x0101_0101_0101_0101_0101_0101_0101_0101;
+const BYTE_HIGH_BITS: u128 = 0xfefe_fefe_fefe_fefe_fefe_fefe_fefe_fefe;
+
+// bytes 14 and 15, the counter of a CTR block
+const COUNTER_MASK: u128 = 0xffff << 112;
+
+pub struct AesUint128 {
+    round_keys      : [FheUint128; 11],
+    execution       : ExecutionConfig,
+    monitor         : Monitor,
+}
+
+impl AesUint128 {
+
+    // initialise
+    pub fn new(round_keys: [FheUint128; 11]) -> Result<AesUint128, FhaesError> {
+        Ok(AesUint128 {
+            round_keys      : round_keys,
+            execution       : ExecutionConfig::default(),
+            monitor         : Monitor::default(),
+        })
+    }
+
+    //set the thread pool and per stage parallelism
+    //(the server key is thread local, call set_server_key on every thread of the pool first)
+    pub fn set_execution_config(&mut self, execution: ExecutionConfig) {
+        self.execution = execution;
+    }
+
+    //set the progress callback and cancellation token, see Monitor (10 rounds per block)
+    pub fn set_monitor(&mut self, monitor: Monitor) {
+        self.monitor = monitor;
+    }
+
+    pub fn add_round_key_fhe(&self, block: &FheUint128, round_no: usize) -> FheUint128 {
+        block ^ &self.round_keys[round_no]
+    }
+
+    // the only place the bytes are taken apart, one univariate lookup table per byte
+    pub fn sub_bytes_fhe(&self, block: &FheUint128) -> FheUint128 {
+        let bytes = self.execution.map(Stage::Bytes, 16, |i| {
+            let offset = u32::try_from(8*i).unwrap();
+            let byte: FheUint8 = (block >> offset).cast_into();
+            let sub: FheUint128 = byte.map(sbox_lookup).cast_into();
+            sub << offset
+        });
+        let mut result = FheUint128::encrypt_trivial(0u128);
+        for byte in bytes {
+            result = result | byte;
+        }
+        result
+    }
+
+    // row r moves r columns to the left, i.e. a rotation of its bytes by 32*r bits
+    pub fn shift_rows_fhe(&self, block: &FheUint128) -> FheUint128 {
+        let mut result = block & row_mask(0, 1);
+        for row in 1..4 {
+            let row_bytes = block & row_mask(row, row+1);
+            result = result | (&row_bytes).rotate_right(32*row);
+        }
+        result
+    }
+
+    // rotates every column up by k rows, row r of the result is row (r+k)%4 of the block
+    fn rotate_columns(&self, block: &FheUint128, k: u32) -> FheUint128 {
+        let down = (block >> (8*k)) & row_mask(0, 4-k);
+        let up = (block << (8*(4-k))) & row_mask(4-k, 4);
+        down | up
+    }
+
+    // xtime of all 16 bytes at once: shift every byte left and add 0x1b where the top bit was set
+    fn xtime_fhe(&self, block: &FheUint128) -> FheUint128 {
+        let h = (block >> 7u32) & BYTE_LOW_BITS;
+        let shifted = (block << 1u32) & BYTE_HIGH_BITS;
+        // h*0x1b, the products cannot cross a byte boundary
+        shifted ^ &h ^ (&h << 1u32) ^ (&h << 3u32) ^ (&h << 4u32)
+    }
+
+    // b_r ^ b_(r+1) ^ a_(r+1) ^ a_(r+2) ^ a_(r+3) of every column, with b = xtime(a)
+    pub fn mix_columns_fhe(&self, block: &FheUint128) -> FheUint128 {
+        let rotated_1 = self.rotate_columns(block, 1);
+        let rotated_2 = self.rotate_columns(block, 2);
+        let rotated_3 = self.rotate_columns(block, 3);
+        self.xtime_fhe(block) ^ self.xtime_fhe(&rotated_1) ^ rotated_1 ^ rotated_2 ^ rotated_3
+    }
+}
+
+pub fn encrypt_block_u128(client_key: &ClientKey, block: &[u8; 16]) -> FheUint128 {
+    FheUint128::encrypt(u128::from_le_bytes(*block), client_key)
+}
+
+pub fn decrypt_block_u128(client_key: &ClientKey, block: &FheUint128) -> [u8; 16] {
+    let value: u128 = block.decrypt(client_key);
+    value.to_le_bytes()
+}
+
+// the 11 round keys of the expanded key, one FheUint128 each
+pub fn round_keys_u128(client_key: &ClientKey, expanded_key: &[u8; 176]) -> [FheUint128; 11] {
+    std::array::from_fn(|i| encrypt_block_u128(client_key, expanded_key[16*i..16*(i+1)].try_into().unwrap()))
+}
+
+impl AesRounds for AesUint128 {
+    type Block = FheUint128;
+    type RoundKey = usize;
+
+    fn execution_config(&self) -> &ExecutionConfig {
+        &self.execution
+    }
+
+    fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    // any FheUint128 is a block
+    fn check_block(&self, _what: &'static str, _block: &FheUint128) -> Result<(), FhaesError> {
+        Ok(())
+    }
+
+    // the counter is xored into the cleared bytes 14 and 15, a single clear scalar operation
+    fn counter_block(&self, iv: &FheUint128, i: usize) -> FheUint128 {
+        let [counter_2, counter_1] = counter_bytes(i).map(u128::from);
+        (iv & !COUNTER_MASK) ^ ((counter_1 << 120) | (counter_2 << 112))
+    }
+
+    // a single xor per block
+    fn segment_iv(&self, iv: &FheUint128, segment: u32) -> FheUint128 {
+        let segment = segment_bytes(segment).into_iter().enumerate().fold(0u128, |acc, (k, byte)| acc | (u128::from(byte) << (8*(10+k))));
+        iv ^ segment
+    }
+
+    fn xor_blocks(&self, a: &FheUint128, b: &FheUint128) -> FheUint128 {
+        a ^ b
+    }
+
+    fn round_key(&self, round_no: usize, _previous: Option<&usize>) -> Result<usize, FhaesError> {
+        Ok(round_no)
+    }
+
+    fn add_round_key(&self, block: &FheUint128, round_no: &usize) -> FheUint128 {
+        self.add_round_key_fhe(block, *round_no)
+    }
+
+    fn round(&self, block: &FheUint128, last: bool) -> FheUint128 {
+        let block = self.shift_rows_fhe(&self.sub_bytes_fhe(block));
+        if last { block } else { self.mix_columns_fhe(&block) }
+    }
+}
+
+impl HomomorphicBlockCipher for AesUint128 {
+    type Key = [FheUint128; 11];
+    type ClientKey = ClientKey;
+
+    fn encrypt_key(client_key: &ClientKey, expanded_key: &[u8; 176]) -> [FheUint128; 11] {
+        round_keys_u128(client_key, expanded_key)
+    }
+
+    fn encrypt_input(client_key: &ClientKey, block: &[u8; 16]) -> FheUint128 {
+        encrypt_block_u128(client_key, block)
+    }
+
+    fn decrypt_output(client_key: &ClientKey, block: &FheUint128) -> [u8; 16] {
+        decrypt_block_u128(client_key, block)
+    }
+}
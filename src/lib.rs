@@ -1,11 +1,27 @@
 pub mod fhaes;
 pub mod utils;
 pub mod fhaes_boolean;
+pub mod fhaes_shortint;
+pub mod shard;
+pub mod parameters;
+pub mod execution;
+pub mod fhaes_uint128;
+pub mod fhaes_fhebool;
+pub mod circuit;
+pub mod cipher;
+pub mod error;
+pub mod reference;
+pub mod bitsliced;
+pub mod blocks;
+pub mod tenants;
+pub mod stream;
+pub mod progress;
 
 use tfhe::{ConfigBuilder, generate_keys, set_server_key, FheUint8};
 use fhaes_boolean::*;
+use fhaes_shortint::*;
 use fhaes::AES;
-use utils::{key_expansion, byte_from_u8};
+use utils::{key_expansion, byte_from_u8, nibbles_from_u8};
 use tfhe::prelude::*;
 use rayon::prelude::*;
 
@@ -16,14 +32,69 @@ pub fn add(left: usize, right: usize) -> usize {
 #[cfg(test)]
 mod tests {
     use std::array;
+    use std::ops::Range;
+
+    use aes::Aes128;
+    use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit, generic_array::GenericArray};
+    use rand::Rng;
 
     use rayon::prelude::*;
-    use tfhe::boolean::{gen_keys, prelude::ServerKey};
+    use tfhe::boolean::{gen_keys, prelude::{Ciphertext, ClientKey, ServerKey, DEFAULT_PARAMETERS}};
+
+    use std::net::TcpListener;
+
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use zeroize::Zeroize;
 
-    use crate::utils::generate_counters;
+    use crate::bitsliced::{pack_blocks, unpack_blocks, BitslicedAes};
+    use crate::blocks::{block_from_hex, block_from_u128, blocks_from_bytes, decrypt_blocks, decrypt_bytes, decrypt_hex, decrypt_u128, encrypt_blocks, encrypt_bytes, encrypt_hex, encrypt_u128, hex_from_block, u128_from_block};
+    use crate::cipher::{segment_iv_bytes, AesRounds, HomomorphicBlockCipher};
+    use crate::circuit::{self, bits_from_u8, u8_from_bits, ClearGates, GateCounter, GateCounts, GenericAes};
+    use crate::error::{check_counter, FhaesError, MAX_CTR_BLOCKS};
+    use crate::execution::{ExecutionConfig, Stage};
+    use crate::fhaes_fhebool::{AesFheBool, FheBoolByte};
+    use crate::fhaes_uint128::{decrypt_block_u128, encrypt_block_u128, round_keys_u128, AesUint128};
+    use crate::parameters::{check_parameter_set, check_parameters, ParameterMismatch, ParameterSet};
+    use tfhe::core_crypto::prelude::DecompositionLevelCount;
+    use crate::progress::{CancellationToken, Monitor, Progress};
+    use crate::reference::{ReferenceAes, INV_SBOX, SBOX};
+    use crate::shard::{serve_worker, ShardCoordinator, ShardRequest, ShardResponse};
+    use crate::stream::{encrypt_ctr_stream, map_stream, BlockReader, BlockWriter};
+    use crate::tenants::{CtrRequest, KeyRegistry};
+    use crate::utils::{compressed_byte_from_u8, decompress_bytes, generate_clear_counters, generate_counters, load_keystream, save_keystream};
 
     use super::*;
 
+    // known answers shared by the backend tests, all under the zero key: the block
+    // 0..16 encrypted once, and as CTR plaintext under an iv of twelve ones and a
+    // zero counter (counter blocks 0 and 1)
+    const BLOCK_EXPECTED: [u8; 16] = [122, 202, 15, 217, 188, 214, 236, 124, 159, 151, 70, 102, 22, 230, 162, 130];
+    const CTR_IV: [u8; 16] = [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0];
+    const CTR_EXPECTED: [[u8; 16]; 2] = [
+        [163, 193, 189, 7, 149, 185, 73, 225, 2, 137, 78, 5, 233, 84, 234, 237],
+        [200, 201, 182, 23, 199, 152, 198, 229, 185, 16, 179, 154, 41, 136, 210, 72]
+    ];
+
+    fn test_block() -> [u8; 16] {
+        array::from_fn(|x| x.try_into().unwrap())
+    }
+
+    // the CTR known answer for `range`, one Vec per block as the tests decode them
+    fn ctr_expected(range: Range<usize>) -> Vec<Vec<u8>> {
+        CTR_EXPECTED[range].iter().map(|x| x.to_vec()).collect()
+    }
+
+    // the boolean backend with the key schedule of `key` encrypted under fresh keys,
+    // and the client key that encrypts and decrypts its blocks
+    fn aes_boolean(key: &[u8; 16]) -> (ClientKey, AesBoolean) {
+        let expanded_key = key_expansion(key);
+        let (client_key, server_key) = gen_keys();
+        let fhe_aes = AesBoolean::new(expanded_key.map(|x| byte_from_u8(&client_key, x)), server_key).unwrap();
+        (client_key, fhe_aes)
+    }
+
     #[test]
     fn it_works() {
         let result = add(2, 2);
@@ -46,12 +117,16 @@ mod tests {
             245, 65, 107, 70, 217, 68, 237, 238, 254, 59, 48, 79, 25, 93, 206, 57,
             236, 28, 165, 127, 53, 88, 72, 145, 203, 99, 120, 222];
         let expanded_key = key_expansion(&key);
-        assert_eq!(expanded_key, expected_result);
+        assert_eq!(*expanded_key, expected_result);
+        // the same schedule on the gates, as BitslicedAes runs it
+        let round_keys = circuit::key_expansion(&ClearGates, &key.map(bits_from_u8)).unwrap();
+        assert_eq!(expected_result.to_vec(), round_keys.iter().map(|x| u8_from_bits(x)).collect::<Vec<u8>>());
+        assert!(matches!(circuit::key_expansion(&ClearGates, &vec![bits_from_u8(0); 15]), Err(FhaesError::InvalidLength { expected: 16, found: 15, .. })));
     }
 
 
     #[test]
-    fn aes_encrypt_one_block_fhe() {
+    fn aes_encrypt_block() {
         let config = ConfigBuilder::default().build();
         let key = [0u8; 16];
         let expanded_key = key_expansion(&key);
@@ -59,16 +134,79 @@ mod tests {
         rayon::broadcast(|_| set_server_key(server_key.clone()));
         set_server_key(server_key.clone());
         let fhe_key_aes = expanded_key.map(|x| FheUint8::encrypt_trivial(x));
-        let mut fhe_aes = AES::new(fhe_key_aes);
-        let block = array::from_fn::<u8, 16, _>(|x|x.try_into().unwrap());
+        let fhe_aes = AES::new(fhe_key_aes).unwrap();
+        let block = test_block();
         let block_enc = block.map(|x| FheUint8::encrypt_trivial(x));
-        fhe_aes.set_aes_key(expanded_key);
-        let sub_clear = fhe_aes.encrypt_one_block(block);
-        println!("sub clear: {:?}", sub_clear);
-        let sub_fhe: Vec::<u8> = fhe_aes.encrypt_one_block_fhe(block_enc).par_iter().map(|x|x.try_decrypt_trivial().unwrap()).collect();
+        let sub_clear = ReferenceAes::from_expanded_key(&expanded_key).encrypt_block_bytes(block);
+        let sub_fhe: Vec::<u8> = fhe_aes.encrypt_block(block_enc).unwrap().par_iter().map(|x|x.try_decrypt_trivial().unwrap()).collect();
+        assert_eq!(sub_clear, sub_fhe[..]);
+    }
+
+    #[test]
+    fn aes_encrypt_one_block_fhe_real_keys() {
+        let config = ConfigBuilder::default().build();
+        let key = [0u8; 16];
+        let expanded_key = key_expansion(&key);
+        let (client_key, server_key) = generate_keys(config);
+        rayon::broadcast(|_| set_server_key(server_key.clone()));
+        set_server_key(server_key.clone());
+        let fhe_key_aes = expanded_key.map(|x| FheUint8::encrypt(x, &client_key));
+        let fhe_aes = AES::new(fhe_key_aes).unwrap();
+        let block = test_block();
+        let block_enc = block.map(|x| FheUint8::encrypt(x, &client_key));
+        let sub_clear = ReferenceAes::from_expanded_key(&expanded_key).encrypt_block_bytes(block);
+        let sub_fhe: Vec::<u8> = fhe_aes.encrypt_block(block_enc).unwrap().par_iter().map(|x|x.decrypt(&client_key)).collect();
         assert_eq!(sub_clear, sub_fhe[..]);
     }
 
+    #[test]
+    fn aes_encrypt_ctr_uint128() {
+        let config = ConfigBuilder::default().build();
+        let key = [0u8; 16];
+        let expanded_key = key_expansion(&key);
+        let (client_key, server_key) = generate_keys(config);
+        rayon::broadcast(|_| set_server_key(server_key.clone()));
+        set_server_key(server_key.clone());
+        let fhe_aes = AesUint128::new(round_keys_u128(&client_key, &expanded_key)).unwrap();
+        let block = test_block();
+        let block_enc = encrypt_block_u128(&client_key, &block);
+
+        let sub_fhe = decrypt_block_u128(&client_key, &fhe_aes.encrypt_block(block_enc.clone()).unwrap());
+        assert_eq!(BLOCK_EXPECTED, sub_fhe);
+
+        let iv = CTR_IV;
+        let iv_fhe = encrypt_block_u128(&client_key, &iv);
+        let ctr_enc: Vec<[u8; 16]> = fhe_aes.encrypt_ctr(vec![block_enc; 2], iv_fhe).unwrap().iter().map(|x| decrypt_block_u128(&client_key, x)).collect();
+        assert_eq!(CTR_EXPECTED.to_vec(), ctr_enc);
+    }
+
+    #[test]
+    fn aes_encrypt_ctr_fhebool() {
+        let config = ConfigBuilder::default().build();
+        let key = [0u8; 16];
+        let expanded_key = key_expansion(&key);
+        let (client_key, server_key) = generate_keys(config);
+        rayon::broadcast(|_| set_server_key(server_key.clone()));
+        set_server_key(server_key.clone());
+        let fhe_aes = AesFheBool::new(expanded_key.map(|x| FheBoolByte::encrypt(&client_key, x))).unwrap();
+        let iv = CTR_IV;
+        let iv_fhe: Vec<FheBoolByte> = iv.iter().map(|x| FheBoolByte::encrypt(&client_key, *x)).collect();
+        let block = test_block();
+        let block_enc: Vec<FheBoolByte> = block.iter().map(|x| FheBoolByte::encrypt(&client_key, *x)).collect();
+
+        let encrypted_blocks = fhe_aes.encrypt_ctr(vec![block_enc; 2], iv_fhe).unwrap();
+        let decoded_encryption: Vec::<Vec<u8>> = encrypted_blocks.
+            par_iter().
+            map(|x|x.par_iter().map(|y| y.decrypt(&client_key)).collect()).collect();
+        assert_eq!(ctr_expected(0..2), decoded_encryption);
+
+        // the output keeps going through FheUint8 arithmetic
+        let sum = encrypted_blocks[0][0].to_fhe_uint8() + FheUint8::encrypt(1u8, &client_key);
+        let sum_clear: u8 = sum.decrypt(&client_key);
+        assert_eq!(164u8, sum_clear);
+        assert_eq!(164u8, FheBoolByte::from_fhe_uint8(&sum).decrypt(&client_key));
+    }
+
     #[test]
     fn aes_ctr_test() {
         let config = ConfigBuilder::default().build();
@@ -78,22 +216,16 @@ mod tests {
         rayon::broadcast(|_| set_server_key(server_key.clone()));
         set_server_key(server_key.clone());
         let fhe_key_aes = expanded_key.map(|x| FheUint8::encrypt_trivial(x));
-        let mut fhe_aes = AES::new(fhe_key_aes);
-        let mut iv = [1u8; 16];
-        iv[15] = 0u8;
-        iv[14] = 0u8;
-        iv[13] = 0u8;
-        iv[12] = 0u8;
+        let fhe_aes = AES::new(fhe_key_aes).unwrap();
+        let iv = CTR_IV;
         let iv_fhe = iv.map(|x| FheUint8::encrypt_trivial(x));
-        let block = array::from_fn::<u8, 16, _>(|x|x.try_into().unwrap());
+        let block = test_block();
         let block_enc = block.map(|x| FheUint8::encrypt_trivial(x));
         let blocks_fhe = vec![block_enc.clone(), block_enc.clone()];
         let blocks = vec![block, block.clone()];
     
-        fhe_aes.set_aes_key(expanded_key);
-        let ctr_enc = fhe_aes.encrypt_ctr_mode(blocks, iv);
-        let ctr_enc_fhe: Vec<Vec<u8>> = fhe_aes.encrypt_ctr_mode_fhe(blocks_fhe, iv_fhe).par_iter().map(|x| x.par_iter().map(|y| y.try_decrypt_trivial().unwrap()).collect()).collect();
-        println!("Decrypted ciphertext: {:?}", ctr_enc);
+        let ctr_enc = ReferenceAes::from_expanded_key(&expanded_key).encrypt_ctr_mode(blocks, iv).unwrap();
+        let ctr_enc_fhe: Vec<Vec<u8>> = fhe_aes.encrypt_ctr(blocks_fhe, iv_fhe).unwrap().par_iter().map(|x| x.par_iter().map(|y| y.try_decrypt_trivial().unwrap()).collect()).collect();
         for i in 0..ctr_enc.len() {
             assert_eq!(ctr_enc[i], ctr_enc_fhe[i][..]);
         }
@@ -101,45 +233,751 @@ mod tests {
 
     #[test]
     fn aes_encrypt_one_block_boolfhe() {
-        let key = [0u8; 16];
-        let expanded_key = key_expansion(&key);
-        let (client_key, server_key) = gen_keys();
-        let fhe_key_aes = expanded_key.map(|x| byte_from_u8(&client_key, x));
-        let t = client_key.encrypt(true);
-        let f = client_key.encrypt(false);
-        let mut fhe_aes = AesBoolean::new(fhe_key_aes, server_key, t, f);
-        let block = array::from_fn::<u8, 16, _>(|x|x.try_into().unwrap());
+        let (client_key, fhe_aes) = aes_boolean(&[0u8; 16]);
+        let block = test_block();
         let block_enc = block.into_par_iter().map(|x| byte_from_u8(&client_key, x)).collect();
-        let sub_fhe: Vec::<u8> = fhe_aes.encrypt_one_block_fhe(block_enc).par_iter().map(|x|x.decrypt(&client_key)).collect();
-        println!("fhe result bool: {:?}", sub_fhe)
+        let sub_fhe: Vec::<u8> = fhe_aes.encrypt_block(block_enc).unwrap().par_iter().map(|x|x.decrypt(&client_key)).collect();
+        assert_eq!(BLOCK_EXPECTED.to_vec(), sub_fhe);
     }
 
     #[test]
     fn aes_encrypt_ctr_boolfhe() {
-        let key = [0u8; 16];
-        let expanded_key = key_expansion(&key);
-        let (client_key, server_key) = gen_keys();
-        let fhe_key_aes = expanded_key.map(|x| byte_from_u8(&client_key, x));
-        let mut iv = [1u8; 16];
-        iv[15] = 0u8;
-        iv[14] = 0u8;
-        iv[13] = 0u8;
-        iv[12] = 0u8;
+        let (client_key, fhe_aes) = aes_boolean(&[0u8; 16]);
+        let iv = CTR_IV;
 
         let iv_fhe: Vec<AesByte> = iv.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
-        let t = client_key.encrypt(true);
-        let f = client_key.encrypt(false);
-        let mut fhe_aes = AesBoolean::new(fhe_key_aes, server_key, t, f);
-        let block = array::from_fn::<u8, 16, _>(|x|x.try_into().unwrap());
+        let block = test_block();
         let block_enc: Vec<AesByte> = block.into_par_iter().map(|x| byte_from_u8(&client_key, x)).collect();
         let block_two = block_enc.clone();
         let blocks = vec![block_enc, block_two.clone()];
         let (counter1, counter2) = generate_counters(&client_key, 2);
-        let encrypted_blocks = fhe_aes.encrypt_ctr_mode_fhe(blocks, iv_fhe, counter1, counter2);
+        let encrypted_blocks = fhe_aes.encrypt_ctr_mode_fhe(blocks, iv_fhe, counter1, counter2).unwrap();
         let decoded_encryption: Vec::<Vec<u8>> = encrypted_blocks.
             par_iter().
             map(|x|x.par_iter().map(|y| y.decrypt(&client_key)).collect()).collect();
-        println!("fhe result bool: {:?}", decoded_encryption);
-        assert_eq!(vec![vec![163u8, 193, 189, 7, 149, 185, 73, 225, 2, 137, 78, 5, 233, 84, 234, 237], vec![200u8, 201, 182, 23, 199, 152, 198, 229, 185, 16, 179, 154, 41, 136, 210, 72]], decoded_encryption);
+        assert_eq!(ctr_expected(0..2), decoded_encryption);
+    }
+
+    #[test]
+    fn reference_aes_matches_aes_crate() {
+        for x in 0..=255u8 {
+            assert_eq!(x, INV_SBOX[usize::from(SBOX[usize::from(x)])]);
+        }
+        let mut rng = rand::rng();
+        for _ in 0..32 {
+            let key: [u8; 16] = rng.random();
+            let block: [u8; 16] = rng.random();
+            let reference = ReferenceAes::new(&GenericArray::from(key));
+            let oracle = Aes128::new(&GenericArray::from(key));
+            assert_eq!(&*key_expansion(&key), reference.expanded_key());
+
+            let mut expected = GenericArray::from(block);
+            oracle.encrypt_block(&mut expected);
+            let mut encrypted = GenericArray::from(block);
+            reference.encrypt_block(&mut encrypted);
+            assert_eq!(expected, encrypted);
+
+            reference.decrypt_block(&mut encrypted);
+            assert_eq!(block, encrypted.as_slice());
+            let mut decrypted = GenericArray::from(block);
+            oracle.decrypt_block(&mut decrypted);
+            assert_eq!(decrypted.as_slice(), reference.decrypt_block_bytes(block));
+        }
+    }
+
+    #[test]
+    fn bitsliced_aes_matches_reference() {
+        let mut rng = rand::rng();
+        let key: [u8; 16] = rng.random();
+        let iv: [u8; 16] = rng.random();
+        // two full batches of lanes and a partial one
+        let blocks: Vec<[u8; 16]> = (0..150).map(|_| rng.random()).collect();
+        assert_eq!(blocks[..40].to_vec(), unpack_blocks(&pack_blocks(&blocks[..40]), 40));
+
+        let reference = ReferenceAes::from_key(&key);
+        let bitsliced = BitslicedAes::from_key(&key).unwrap();
+        let expected: Vec<[u8; 16]> = blocks.iter().map(|x| reference.encrypt_block_bytes(*x)).collect();
+        assert_eq!(expected, bitsliced.encrypt_blocks(&blocks).unwrap());
+        assert_eq!(reference.encrypt_ctr_mode(blocks.clone(), iv).unwrap(), bitsliced.encrypt_ctr_mode(blocks.clone(), iv).unwrap());
+        assert!(matches!(bitsliced.encrypt_ctr_mode(vec![[0u8; 16]; 257], iv), Err(FhaesError::CounterOverflow { blocks: 257 })));
+    }
+
+    #[test]
+    fn round_keys_zeroize() {
+        let key: [u8; 16] = rand::rng().random();
+        let expanded_key = key_expansion(&key);
+        let mut fhe_aes = GenericAes::new(ClearGates, expanded_key.iter().map(|x| bits_from_u8(*x)).collect()).unwrap();
+        fhe_aes.zeroize();
+        // all round keys are zero afterwards
+        let block: Vec<Vec<bool>> = (0..16).map(|x| bits_from_u8(x)).collect();
+        let result: Vec<u8> = fhe_aes.encrypt_block(block).unwrap().iter().map(|x| u8_from_bits(x)).collect();
+        assert_eq!(result, ReferenceAes::from_expanded_key(&[0u8; 176]).encrypt_block_bytes(test_block()));
+    }
+
+    #[test]
+    fn generic_aes_progress_and_cancellation() {
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let mut fhe_aes = GenericAes::new(ClearGates, expanded_key.iter().map(|x| bits_from_u8(*x)).collect()).unwrap();
+        let block: Vec<Vec<bool>> = (0..16).map(|x| bits_from_u8(x)).collect();
+
+        // every keystream block is a full 10 round encryption
+        let monitor = Monitor::new();
+        fhe_aes.set_monitor(monitor.clone());
+        fhe_aes.generate_keystream(&block, 0..3).unwrap();
+        assert_eq!((30, 30), (monitor.progress().rounds_done, monitor.progress().rounds_total));
+
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let monitor = Monitor::new().with_cancellation(token).with_callback(move |progress: Progress| {
+            if (progress.rounds_done, progress.rounds_total) == (3, 10) {
+                cancel.cancel();
+            }
+        });
+        fhe_aes.set_monitor(monitor.clone());
+        assert!(matches!(fhe_aes.encrypt_block(block.clone()), Err(FhaesError::Cancelled)));
+        assert_eq!((3, 10), (monitor.progress().rounds_done, monitor.progress().rounds_total));
+
+        // the next call is a run of its own, the 7 rounds the cancelled one left are gone
+        monitor.token().reset();
+        fhe_aes.generate_keystream(&block, 0..2).unwrap();
+        assert_eq!((20, 20), (monitor.progress().rounds_done, monitor.progress().rounds_total));
+    }
+
+    #[test]
+    fn block_conversions() {
+        let block = test_block();
+        assert_eq!(block, block_from_u128(0x000102030405060708090a0b0c0d0e0f));
+        assert_eq!(0x000102030405060708090a0b0c0d0e0f, u128_from_block(&block));
+        assert_eq!("000102030405060708090a0b0c0d0e0f", hex_from_block(&block));
+        assert_eq!(block, block_from_hex("000102030405060708090a0b0c0d0e0f").unwrap());
+        assert!(matches!(block_from_hex("0001"), Err(FhaesError::InvalidHex(_))));
+        assert!(matches!(blocks_from_bytes(&[0u8; 20]), Err(FhaesError::InvalidLength { expected: 32, found: 20, .. })));
+
+        let (client_key, _) = gen_keys();
+        let message: Vec<u8> = (0..32).collect();
+        let encrypted = encrypt_bytes::<AesBoolean>(&client_key, &message).unwrap();
+        assert_eq!(2, encrypted.len());
+        assert_eq!(message, decrypt_bytes::<AesBoolean>(&client_key, &encrypted));
+        assert_eq!(vec![block; 2], decrypt_blocks::<AesBoolean>(&client_key, &encrypt_blocks::<AesBoolean>(&client_key, &[block; 2])));
+        assert_eq!(7u128 << 100, decrypt_u128::<AesBoolean>(&client_key, &encrypt_u128::<AesBoolean>(&client_key, 7u128 << 100)));
+
+        let config = ConfigBuilder::default().build();
+        let (client_key, _) = generate_keys(config);
+        let encrypted = encrypt_hex::<AES>(&client_key, "000102030405060708090a0b0c0d0e0f").unwrap();
+        assert_eq!("000102030405060708090a0b0c0d0e0f", decrypt_hex::<AES>(&client_key, &encrypted));
+    }
+
+    #[test]
+    fn aes_boolean_ctr_bytes() {
+        let key = [0u8; 16];
+        let (client_key, fhe_aes) = aes_boolean(&key);
+        let iv = CTR_IV;
+        let iv_clear: Vec<AesByte> = iv.iter().map(|x| AesByte::from_clear(*x)).collect();
+
+        // one full block and 4 bytes of the second
+        let message: Vec<u8> = (0..20).collect();
+        let message_enc: Vec<AesByte> = message.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+        let encrypted: Vec<u8> = fhe_aes.encrypt_ctr_bytes_fhe(&message_enc, &iv_clear).unwrap().iter().map(|x| x.decrypt(&client_key)).collect();
+        let expected = ReferenceAes::from_key(&key).encrypt_ctr_bytes(&message, iv).unwrap();
+        assert_eq!(expected, encrypted);
+        // the first keystream bytes of block 1 are those of the CTR known answer
+        let keystream: Vec<u8> = expected[16..].iter().zip(&message[16..]).map(|(x, y)| x^y).collect();
+        assert_eq!(CTR_EXPECTED[1][..4], keystream[..]);
+
+        assert!(fhe_aes.encrypt_ctr_bytes_fhe(&Vec::new(), &iv_clear).unwrap().is_empty());
+        assert!(matches!(fhe_aes.generate_keystream_bytes_fhe(&iv_clear, 16*256 + 1), Err(FhaesError::CounterOverflow { blocks: 257 })));
+    }
+
+    #[test]
+    fn reference_ctr_range_matches_stream() {
+        let mut rng = rand::rng();
+        let reference = ReferenceAes::from_key(&rng.random());
+        let iv: [u8; 16] = rng.random();
+        // past byte 256, where the counter carries from iv[15] into iv[14]
+        let message: Vec<u8> = (0..300).map(|_| rng.random()).collect();
+        let stream = reference.encrypt_ctr_bytes(&message, iv).unwrap();
+        for _ in 0..32 {
+            let start = rng.random_range(0..300);
+            let end = rng.random_range(start..=300);
+            assert_eq!(stream[start..end], reference.encrypt_ctr_range(&message[start..end], iv, start).unwrap());
+        }
+        for (start, end) in [(250, 262), (255, 257), (256, 256), (240, 300)] {
+            assert_eq!(stream[start..end], reference.encrypt_ctr_range(&message[start..end], iv, start).unwrap());
+        }
+        // a tail that is not a whole block is the prefix of the whole block stream
+        assert_eq!(stream[..33], reference.encrypt_ctr_bytes(&message[..33], iv).unwrap());
+        assert!(matches!(reference.encrypt_ctr_range(&[0u8; 2], iv, 16*256 - 1), Err(FhaesError::CounterOverflow { blocks: 257 })));
+    }
+
+    #[test]
+    fn aes_boolean_ctr_range() {
+        let key = [0u8; 16];
+        let (client_key, fhe_aes) = aes_boolean(&key);
+        let iv = [1u8; 16];
+        let iv_clear: Vec<AesByte> = iv.iter().map(|x| AesByte::from_clear(*x)).collect();
+
+        // partial blocks at both ends, counters 1 and 2 only
+        let message: Vec<u8> = (0..48).collect();
+        let stream = ReferenceAes::from_key(&key).encrypt_ctr_bytes(&message, iv).unwrap();
+        let region: Vec<AesByte> = message[21..40].iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+        let patched: Vec<u8> = fhe_aes.encrypt_ctr_range_fhe(&region, &iv_clear, 21).unwrap().iter().map(|x| x.decrypt(&client_key)).collect();
+        assert_eq!(stream[21..40], patched);
+
+        // the tail of counter 15 and the head of counter 16, across the carry into iv[14]
+        let message: Vec<u8> = (0..262).map(|x: usize| u8::try_from(x & 0xff).unwrap()).collect();
+        let stream = ReferenceAes::from_key(&key).encrypt_ctr_bytes(&message, iv).unwrap();
+        let region: Vec<AesByte> = message[250..262].iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+        let patched: Vec<u8> = fhe_aes.encrypt_ctr_range_fhe(&region, &iv_clear, 250).unwrap().iter().map(|x| x.decrypt(&client_key)).collect();
+        assert_eq!(stream[250..262], patched);
+    }
+
+    #[test]
+    fn key_registry_batch() {
+        // two tenants with their own FHE keys, AES keys and monitors
+        let keys = [[0u8; 16], [7u8; 16]];
+        let mut registry = KeyRegistry::new();
+        let mut tenants = Vec::new();
+        for key in keys.iter() {
+            let (client_key, fhe_aes) = aes_boolean(key);
+            let monitor = Monitor::new();
+            let handle = registry.register(fhe_aes.with_monitor(monitor.clone()));
+            tenants.push((handle, client_key, monitor));
+        }
+        assert_eq!(2, registry.len());
+
+        // one block for the first tenant, two for the second
+        let iv = [1u8; 16];
+        let block = test_block();
+        let requests: Vec<CtrRequest> = tenants.iter().enumerate().map(|(t, (handle, client_key, _))| CtrRequest {
+            key: *handle,
+            iv: iv.iter().map(|x| AesByte::from_clear(*x)).collect(),
+            blocks: vec![block.iter().map(|x| byte_from_u8(client_key, *x)).collect(); t + 1],
+        }).collect();
+        let results = registry.encrypt_ctr_batch_fhe(&requests).unwrap();
+        for (t, (key, (_, client_key, monitor))) in keys.iter().zip(tenants.iter()).enumerate() {
+            let decrypted: Vec<[u8; 16]> = results[t].iter().map(|x| array::from_fn(|i| x[i].decrypt(client_key))).collect();
+            assert_eq!(ReferenceAes::from_key(key).encrypt_ctr_mode(vec![block; t + 1], iv).unwrap(), decrypted);
+            // every tenant's monitor only sees the rounds of its own blocks, 9 per cached counter block
+            assert_eq!((9*(t + 1), 9*(t + 1)), (monitor.progress().rounds_done, monitor.progress().rounds_total));
+        }
+
+        // a tenant on another parameter set rejects the blocks of the others before any work
+        let (_, server_key) = ParameterSet::TfheLib.gen_keys();
+        let other_monitor = Monitor::new();
+        let other = AesBoolean::builder(server_key)
+            .with_key_schedule(key_expansion(&[0u8; 16]).map(AesByte::from_clear))
+            .with_parameters(ParameterSet::TfheLib.boolean_parameters())
+            .build()
+            .unwrap()
+            .with_monitor(other_monitor.clone());
+        let other_handle = registry.register(other);
+        let mismatched = CtrRequest {
+            key: other_handle,
+            iv: requests[0].iv.clone(),
+            blocks: requests[0].blocks.clone(),
+        };
+        assert!(matches!(registry.encrypt_ctr_batch_fhe(&[mismatched]), Err(FhaesError::ParameterMismatch(_))));
+        assert!(matches!(registry.encrypt_blocks_fhe(&[(other_handle, requests[0].blocks[0].clone())]), Err(FhaesError::ParameterMismatch(_))));
+        assert_eq!(0, other_monitor.progress().rounds_total);
+
+        // a removed tenant fails the whole batch before any work, and its handle is not reused
+        let (handle, _, ref monitor) = tenants[1];
+        assert!(registry.remove(handle).is_some());
+        assert!(registry.remove(handle).is_none());
+        assert!(matches!(registry.get(handle), Err(FhaesError::UnknownKey { .. })));
+        assert!(matches!(registry.encrypt_ctr_batch_fhe(&requests), Err(FhaesError::UnknownKey { .. })));
+        assert!(matches!(registry.encrypt_blocks_fhe(&[(handle, Vec::new())]), Err(FhaesError::UnknownKey { .. })));
+        // the failed batches announced nothing, the monitors still show the first batch
+        assert_eq!((9, 9), (tenants[0].2.progress().rounds_done, tenants[0].2.progress().rounds_total));
+        assert_eq!((18, 18), (monitor.progress().rounds_done, monitor.progress().rounds_total));
+        let (_, fhe_aes) = aes_boolean(&keys[1]);
+        assert_ne!(handle, registry.register(fhe_aes));
+        assert!(matches!(registry.get(handle), Err(FhaesError::UnknownKey { .. })));
+    }
+
+    #[test]
+    fn block_stream_batches() {
+        let mut rng = rand::rng();
+        let reference = ReferenceAes::from_key(&rng.random());
+        let blocks: Vec<[u8; 16]> = (0..10).map(|_| rng.random()).collect();
+        let mut writer = BlockWriter::new(Vec::new());
+        writer.write_batch(&blocks).unwrap();
+        let input = writer.finish().unwrap();
+
+        let mut batches = Vec::new();
+        let mut output = Vec::new();
+        let num_blocks = map_stream(&input[..], &mut output, 4, 16, |first_block, batch: Vec<[u8; 16]>| {
+            batches.push((first_block, batch.len()));
+            Ok(batch.iter().map(|x| reference.encrypt_block_bytes(*x)).collect::<Vec<[u8; 16]>>())
+        }).unwrap();
+        assert_eq!(10, num_blocks);
+        assert_eq!(vec![(0, 4), (4, 4), (8, 2)], batches);
+        let encrypted: Vec<[u8; 16]> = BlockReader::new(&output[..], 16).read_batch(usize::MAX).unwrap();
+        assert_eq!(blocks.iter().map(|x| reference.encrypt_block_bytes(*x)).collect::<Vec<[u8; 16]>>(), encrypted);
+
+        // a block cut off at the end of the input
+        let mut reader = BlockReader::new(&input[..input.len() - 1], 16);
+        assert!(matches!(reader.read_batch::<[u8; 16]>(usize::MAX), Err(FhaesError::Deserialization(_))));
+
+        // blocks over the limit, and a length prefix that would allocate 2^64 bytes
+        let mut reader = BlockReader::new(&input[..], 15);
+        assert!(matches!(reader.read_batch::<[u8; 16]>(1), Err(FhaesError::Deserialization(_))));
+        let mut reader = BlockReader::new(&u64::MAX.to_le_bytes()[..], 1024);
+        assert!(matches!(reader.read_batch::<Vec<u8>>(1), Err(FhaesError::Deserialization(_))));
+    }
+
+    #[test]
+    fn aes_boolean_ctr_stream() {
+        let key = [0u8; 16];
+        let (client_key, fhe_aes) = aes_boolean(&key);
+        let iv = [1u8; 16];
+        let iv_clear: Vec<AesByte> = iv.iter().map(|x| AesByte::from_clear(*x)).collect();
+
+        // two batches, the second one starts at counter 2
+        let block = test_block();
+        let block_enc: Vec<AesByte> = block.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+        let block_bytes = bincode::serialized_size(&block_enc).unwrap();
+        let mut writer = BlockWriter::new(Vec::new());
+        writer.write_batch(&vec![block_enc; 3]).unwrap();
+        let input = writer.finish().unwrap();
+        let mut output = Vec::new();
+        assert_eq!(3, encrypt_ctr_stream(&fhe_aes, &iv_clear, &input[..], &mut output, 2, block_bytes).unwrap());
+
+        let encrypted: Vec<Vec<AesByte>> = BlockReader::new(&output[..], block_bytes).read_batch(usize::MAX).unwrap();
+        let decrypted: Vec<[u8; 16]> = encrypted.iter().map(|x| array::from_fn(|i| x[i].decrypt(&client_key))).collect();
+        assert_eq!(ReferenceAes::from_key(&key).encrypt_ctr_mode(vec![block; 3], iv).unwrap(), decrypted);
+    }
+
+    #[test]
+    fn ctr_stream_moves_on_to_the_next_segment() {
+        // a public key schedule and public blocks fold every gate, which keeps 260 blocks cheap
+        let key = [0u8; 16];
+        let (client_key, server_key) = gen_keys();
+        let fhe_aes = AesBoolean::new(key_expansion(&key).map(AesByte::from_clear), server_key).unwrap();
+        let iv = [1u8; 16];
+        let iv_clear: Vec<AesByte> = iv.iter().map(|x| AesByte::from_clear(*x)).collect();
+
+        // the batch 200..260 straddles the end of segment 0
+        let mut rng = rand::rng();
+        let blocks: Vec<[u8; 16]> = (0..260).map(|_| rng.random()).collect();
+        let blocks_clear: Vec<Vec<AesByte>> = blocks.iter().map(|x| x.iter().map(|y| AesByte::from_clear(*y)).collect()).collect();
+        let block_bytes = bincode::serialized_size(&blocks_clear[0]).unwrap();
+        let mut writer = BlockWriter::new(Vec::new());
+        writer.write_batch(&blocks_clear).unwrap();
+        let input = writer.finish().unwrap();
+        let mut output = Vec::new();
+        assert_eq!(260, encrypt_ctr_stream(&fhe_aes, &iv_clear, &input[..], &mut output, 100, block_bytes).unwrap());
+
+        let encrypted: Vec<Vec<AesByte>> = BlockReader::new(&output[..], block_bytes).read_batch(usize::MAX).unwrap();
+        let decrypted: Vec<[u8; 16]> = encrypted.iter().map(|x| array::from_fn(|i| x[i].decrypt(&client_key))).collect();
+        let reference = ReferenceAes::from_key(&key);
+        let mut expected = reference.encrypt_ctr_mode(blocks[..256].to_vec(), iv).unwrap();
+        expected.extend(reference.encrypt_ctr_mode(blocks[256..].to_vec(), segment_iv_bytes(&iv, 1)).unwrap());
+        assert_eq!(expected, decrypted);
+    }
+
+    #[test]
+    fn aes_boolean_progress_and_cancellation() {
+        let key = [0u8; 16];
+        let (client_key, fhe_aes) = aes_boolean(&key);
+        let block = test_block();
+        let block_enc: Vec<AesByte> = block.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+
+        // the job runner cancels after 3 rounds, the block stops before round 4
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let monitor = Monitor::new().with_cancellation(token).with_callback(move |progress: Progress| {
+            if progress.rounds_done == 3 {
+                cancel.cancel();
+            }
+        });
+        let fhe_aes = fhe_aes.with_monitor(monitor.clone());
+        assert!(matches!(fhe_aes.encrypt_block(block_enc.clone()), Err(FhaesError::Cancelled)));
+        assert_eq!((3, 10), (monitor.progress().rounds_done, monitor.progress().rounds_total));
+
+        // a full run reports every round and ends with nothing left
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let seen = updates.clone();
+        let fhe_aes = fhe_aes.with_monitor(Monitor::new().with_callback(move |progress: Progress| seen.lock().unwrap().push(progress)));
+        let encrypted: Vec<u8> = fhe_aes.encrypt_block(block_enc).unwrap().iter().map(|x| x.decrypt(&client_key)).collect();
+        assert_eq!(ReferenceAes::from_key(&key).encrypt_block_bytes(block).to_vec(), encrypted);
+        let updates = updates.lock().unwrap();
+        assert_eq!((1..=10).collect::<Vec<usize>>(), updates.iter().map(|x| x.rounds_done).collect::<Vec<usize>>());
+        assert!(updates.iter().all(|x| x.rounds_total == 10));
+        assert_eq!(Some(Duration::ZERO), updates[9].eta);
+    }
+
+    #[test]
+    fn aes_encrypt_one_block_shortint() {
+        let key = [0u8; 16];
+        let expanded_key = key_expansion(&key);
+        let (client_key, server_key) = tfhe::shortint::gen_keys(tfhe::shortint::parameters::PARAM_MESSAGE_4_CARRY_4_KS_PBS);
+        let fhe_key_aes = expanded_key.map(|x| nibbles_from_u8(&client_key, x));
+        let mut fhe_aes = AesShortint::new(fhe_key_aes, server_key).unwrap();
+        // both nibbles of a byte in parallel, on a pool of its own
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+        fhe_aes.set_execution_config(ExecutionConfig::default().with_thread_pool(pool).with_threshold(Stage::Bits, 0));
+        let block = test_block();
+        let block_enc = block.iter().map(|x| nibbles_from_u8(&client_key, *x)).collect();
+        let sub_fhe: Vec::<u8> = fhe_aes.encrypt_block(block_enc).unwrap().par_iter().map(|x|x.decrypt(&client_key)).collect();
+
+        let mut expected = GenericArray::from(block);
+        Aes128::new(&GenericArray::from(key)).encrypt_block(&mut expected);
+        assert_eq!(expected.as_slice(), sub_fhe[..]);
+    }
+
+    // CTR known answer through the shared trait only
+    fn ctr_known_answer<C: HomomorphicBlockCipher>(fhe_aes: &C, client_key: &C::ClientKey) {
+        let iv = CTR_IV;
+        let block = test_block();
+        let blocks = vec![C::encrypt_input(client_key, &block); 2];
+        let encrypted = fhe_aes.encrypt_ctr(blocks, C::encrypt_input(client_key, &iv)).unwrap();
+        let decoded: Vec<[u8; 16]> = encrypted.iter().map(|x| C::decrypt_output(client_key, x)).collect();
+        assert_eq!(CTR_EXPECTED.to_vec(), decoded);
+    }
+
+    #[test]
+    fn homomorphic_block_cipher_ctr() {
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let (client_key, server_key) = tfhe::shortint::gen_keys(tfhe::shortint::parameters::PARAM_MESSAGE_4_CARRY_4_KS_PBS);
+        let fhe_aes = AesShortint::new(AesShortint::encrypt_key(&client_key, &expanded_key), server_key).unwrap();
+        ctr_known_answer(&fhe_aes, &client_key);
+
+        let (client_key, server_key) = gen_keys();
+        let fhe_aes = AesBoolean::new(AesBoolean::encrypt_key(&client_key, &expanded_key), server_key).unwrap();
+        ctr_known_answer(&fhe_aes, &client_key);
+    }
+
+    #[test]
+    fn aes_encrypt_ctr_boolfhe_public_iv() {
+        let (client_key, fhe_aes) = aes_boolean(&[0u8; 16]);
+        let iv = CTR_IV;
+
+        let iv_clear: Vec<AesByte> = iv.iter().map(|x| AesByte::from_clear(*x)).collect();
+        let block = test_block();
+        let blocks: Vec<Vec<AesByte>> = vec![block.iter().map(|x| AesByte::from_clear(*x)).collect(); 2];
+        let (counter1, counter2) = generate_clear_counters(2);
+        let encrypted_blocks = fhe_aes.encrypt_ctr_mode_fhe(blocks, iv_clear, counter1, counter2).unwrap();
+        let decoded_encryption: Vec::<Vec<u8>> = encrypted_blocks.
+            par_iter().
+            map(|x|x.par_iter().map(|y| y.decrypt(&client_key)).collect()).collect();
+        assert_eq!(ctr_expected(0..2), decoded_encryption);
+    }
+
+    #[test]
+    fn aes_keystream_boolfhe() {
+        let (client_key, fhe_aes) = aes_boolean(&[0u8; 16]);
+        let iv = CTR_IV;
+
+        let iv_fhe: Vec<AesByte> = iv.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+        let keystream = fhe_aes.generate_keystream(&iv_fhe, 1..2).unwrap();
+        let path = std::env::temp_dir().join("fhaes_keystream_test.bin");
+        assert!(save_keystream(&path, &fhe_aes.parameters(), 1..3, &keystream).is_err());
+        save_keystream(&path, &fhe_aes.parameters(), 1..2, &keystream).unwrap();
+        // a keystream of another parameter set is rejected before its blocks are read
+        assert!(matches!(load_keystream(&path, &ParameterSet::TfheLib.boolean_parameters()), Err(FhaesError::ParameterMismatch(_))));
+        let (range, keystream) = load_keystream(&path, &fhe_aes.parameters()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(1..2, range);
+
+        let block = test_block();
+        let blocks: Vec<Vec<AesByte>> = vec![block.iter().map(|x| AesByte::from_clear(*x)).collect()];
+        let decoded_encryption: Vec::<Vec<u8>> = fhe_aes.apply_keystream(&blocks, &keystream).unwrap().
+            par_iter().
+            map(|x|x.par_iter().map(|y| y.decrypt(&client_key)).collect()).collect();
+        assert_eq!(ctr_expected(1..2), decoded_encryption);
+    }
+
+    #[test]
+    fn sharded_ctr_boolfhe() {
+        let key = [0u8; 16];
+        let expanded_key = key_expansion(&key);
+        let (client_key, server_key) = gen_keys();
+        let fhe_key_aes = expanded_key.map(|x| byte_from_u8(&client_key, x));
+        let iv = CTR_IV;
+
+        // two healthy workers and one that drops the connection after Setup
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            std::thread::spawn(move || serve_worker(listener).unwrap());
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        addrs.push(listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let _: ShardRequest = bincode::deserialize_from(&mut reader).unwrap();
+            bincode::serialize_into(&stream, &ShardResponse::Ready { parameters: DEFAULT_PARAMETERS }).unwrap();
+        });
+
+        let mut coordinator = ShardCoordinator::connect(&addrs, &server_key, &fhe_key_aes, DEFAULT_PARAMETERS, 1).unwrap();
+        let iv_fhe: Vec<AesByte> = iv.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+        let block = test_block();
+        let blocks: Vec<Vec<AesByte>> = vec![block.iter().map(|x| byte_from_u8(&client_key, *x)).collect(); 2];
+        let decoded_encryption: Vec::<Vec<u8>> = coordinator.encrypt_ctr_mode_fhe(blocks, iv_fhe).unwrap().
+            par_iter().
+            map(|x|x.par_iter().map(|y| y.decrypt(&client_key)).collect()).collect();
+        assert_eq!(ctr_expected(0..2), decoded_encryption);
+    }
+
+    #[test]
+    fn sharded_ctr_parameter_mismatch() {
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let (client_key, server_key) = gen_keys();
+        let fhe_key_aes = expanded_key.map(|x| byte_from_u8(&client_key, x));
+
+        // a worker that built its AesBoolean with another parameter set
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let _: ShardRequest = bincode::deserialize_from(&mut reader).unwrap();
+            bincode::serialize_into(&stream, &ShardResponse::Ready { parameters: ParameterSet::TfheLib.boolean_parameters() }).unwrap();
+        });
+        let coordinator = ShardCoordinator::connect(&[addr], &server_key, &fhe_key_aes, DEFAULT_PARAMETERS, 1);
+        assert!(matches!(coordinator, Err(FhaesError::ParameterMismatch(_))));
+    }
+
+    // a worker that answers every Encrypt with `respond(shard_id)`
+    fn fake_worker<F: Fn(usize) -> ShardResponse + Send + 'static>(respond: F) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let _: ShardRequest = bincode::deserialize_from(&mut reader).unwrap();
+            bincode::serialize_into(&stream, &ShardResponse::Ready { parameters: DEFAULT_PARAMETERS }).unwrap();
+            while let Ok(ShardRequest::Encrypt { shard_id, .. }) = bincode::deserialize_from::<_, ShardRequest>(&mut reader) {
+                bincode::serialize_into(&stream, &respond(shard_id)).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn sharded_ctr_worker_errors() {
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let (client_key, server_key) = gen_keys();
+        let fhe_key_aes = expanded_key.map(|x| byte_from_u8(&client_key, x));
+        let iv: Vec<AesByte> = CTR_IV.iter().map(|x| AesByte::from_clear(*x)).collect();
+        let blocks: Vec<Vec<AesByte>> = vec![test_block().iter().map(|x| AesByte::from_clear(*x)).collect(); 2];
+
+        // malformed input is rejected before any shard is sent
+        let mut coordinator = ShardCoordinator::connect(&[fake_worker(|_| unreachable!())], &server_key, &fhe_key_aes, DEFAULT_PARAMETERS, 1).unwrap();
+        let mut narrow = blocks.clone();
+        narrow[1][4] = AesByte::new(narrow[1][4].get_bits()[..7].to_vec());
+        assert!(matches!(coordinator.encrypt_ctr_mode_fhe(narrow, iv.clone()), Err(FhaesError::InvalidLength { expected: 8, found: 7, .. })));
+        assert!(matches!(coordinator.encrypt_ctr_mode_fhe(blocks.clone(), iv[..15].to_vec()), Err(FhaesError::InvalidLength { expected: 16, found: 15, .. })));
+
+        // a rejected shard is reported, not retried until every worker is gone
+        let rejecting = fake_worker(|_| ShardResponse::Error("rejected".to_string()));
+        let mut coordinator = ShardCoordinator::connect(&[rejecting], &server_key, &fhe_key_aes, DEFAULT_PARAMETERS, 1).unwrap();
+        match coordinator.encrypt_ctr_mode_fhe(blocks.clone(), iv.clone()) {
+            Err(FhaesError::Worker { message }) => assert_eq!("rejected", message),
+            _ => panic!("expected the error of the worker")
+        }
+        assert_eq!(1, coordinator.num_workers());
+
+        // a result for another shard than the one sent is an error, not stored under its id
+        let shifted = fake_worker(|shard_id| ShardResponse::Encrypted { shard_id: shard_id + 1, blocks: Vec::new() });
+        let mut coordinator = ShardCoordinator::connect(&[shifted], &server_key, &fhe_key_aes, DEFAULT_PARAMETERS, 1).unwrap();
+        assert!(matches!(coordinator.encrypt_ctr_mode_fhe(blocks, iv), Err(FhaesError::Io(_))));
+        assert_eq!(0, coordinator.num_workers());
+    }
+
+    #[test]
+    fn aes_encrypt_one_block_boolfhe_round_key_storage() {
+        let key = [0u8; 16];
+        let expanded_key = key_expansion(&key);
+        let (client_key, server_key) = gen_keys();
+        let block = test_block();
+        let block_enc: Vec<AesByte> = block.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+
+        let streamed = AesBoolean::new_streamed(key.map(|x| byte_from_u8(&client_key, x)), server_key.clone()).unwrap();
+        let path = PagedRoundKeys::temp_path();
+        let paged = AesBoolean::new_paged(expanded_key.map(|x| byte_from_u8(&client_key, x)), path.clone(), server_key.clone()).unwrap();
+        // the file is never overwritten
+        assert!(AesBoolean::new_paged(expanded_key.map(|x| byte_from_u8(&client_key, x)), path.clone(), server_key).is_err());
+        assert!(matches!(paged.round_key_fhe(11), Err(FhaesError::InvalidLength { what: "rounds", .. })));
+        for fhe_aes in [streamed, paged] {
+            let sub_fhe: Vec::<u8> = fhe_aes.encrypt_block(block_enc.clone()).unwrap().par_iter().map(|x|x.decrypt(&client_key)).collect();
+            assert_eq!(BLOCK_EXPECTED.to_vec(), sub_fhe);
+        }
+        // removed with the AesBoolean
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn aes_boolean_builder_server_only() {
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let (client_key, server_key) = gen_keys();
+        // the key schedule as the server would load it, no other client input
+        let stored = bincode::serialize(&expanded_key.iter().map(|x| byte_from_u8(&client_key, *x)).collect::<Vec<AesByte>>()).unwrap();
+        let key_schedule: Vec<AesByte> = bincode::deserialize(&stored).unwrap();
+
+        let short = AesBoolean::builder(server_key.clone()).with_key_schedule(key_schedule[..175].to_vec()).build();
+        assert!(matches!(short, Err(FhaesError::InvalidLength { expected: 176, found: 175, .. })));
+        let mut narrow = key_schedule.clone();
+        narrow[3] = AesByte::new(narrow[3].get_bits()[..7].to_vec());
+        let narrow = AesBoolean::builder(server_key.clone()).with_key_schedule(narrow).build();
+        assert!(matches!(narrow, Err(FhaesError::InvalidLength { expected: 8, found: 7, .. })));
+        assert!(matches!(AesBoolean::builder(server_key.clone()).build(), Err(FhaesError::InvalidLength { expected: 176, found: 0, .. })));
+
+        let fhe_aes = AesBoolean::builder(server_key).with_key_schedule(key_schedule).build().unwrap();
+        // short bytes in a block or an iv are errors, not out of bounds panics
+        let mut narrow_block: Vec<AesByte> = (0..16u8).map(AesByte::from_clear).collect();
+        narrow_block[5] = AesByte::new(narrow_block[5].get_bits()[..7].to_vec());
+        assert!(matches!(fhe_aes.encrypt_block(narrow_block.clone()), Err(FhaesError::InvalidLength { expected: 8, found: 7, .. })));
+        assert!(matches!(fhe_aes.generate_keystream(&narrow_block, 0..1), Err(FhaesError::InvalidLength { expected: 8, found: 7, .. })));
+        assert!(matches!(fhe_aes.ctr_prefix_cache(&narrow_block), Err(FhaesError::InvalidLength { expected: 8, found: 7, .. })));
+
+        let block: Vec<AesByte> = (0..16u8).map(|x| byte_from_u8(&client_key, x)).collect();
+        let sub_fhe: Vec::<u8> = fhe_aes.encrypt_block(block).unwrap().par_iter().map(|x|x.decrypt(&client_key)).collect();
+        assert_eq!(BLOCK_EXPECTED.to_vec(), sub_fhe);
+    }
+
+    #[test]
+    fn aes_encrypt_one_block_boolfhe_compressed_upload() {
+        let key = [0u8; 16];
+        let expanded_key = key_expansion(&key);
+        let (client_key, server_key) = gen_keys();
+        let block = test_block();
+        let block_upload: Vec<CompressedAesByte> = block.iter().map(|x| compressed_byte_from_u8(&client_key, *x)).collect();
+        let key_upload: Vec<CompressedAesByte> = expanded_key.iter().map(|x| compressed_byte_from_u8(&client_key, *x)).collect();
+
+        let block_enc = decompress_bytes(&block_upload);
+        let compressed_size = bincode::serialized_size(&block_upload).unwrap();
+        let full_size = bincode::serialized_size(&block_enc).unwrap();
+        assert!(compressed_size*10 < full_size);
+
+        let fhe_key_aes: [AesByte; 176] = decompress_bytes(&key_upload).try_into().ok().unwrap();
+        let fhe_aes = AesBoolean::new(fhe_key_aes, server_key).unwrap();
+        let sub_fhe: Vec::<u8> = fhe_aes.encrypt_block(block_enc).unwrap().par_iter().map(|x|x.decrypt(&client_key)).collect();
+        assert_eq!(BLOCK_EXPECTED.to_vec(), sub_fhe);
+    }
+
+    #[test]
+    fn generic_aes_gate_counter() {
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let fhe_aes = GenericAes::new(GateCounter::default(), expanded_key.iter().map(|x| bits_from_u8(*x)).collect()).unwrap();
+        let block: Vec<Vec<bool>> = (0..16u8).map(bits_from_u8).collect();
+        let encrypted: Vec<u8> = fhe_aes.encrypt_block(block).unwrap().iter().map(|x| u8_from_bits(x)).collect();
+        assert_eq!(BLOCK_EXPECTED.to_vec(), encrypted);
+        // 160 SBoxes of 32 and, 81 xor and 4 not, 9 MixColumns of 560 xor and 11 AddRoundKey of 128 xor
+        assert_eq!(GateCounts { xor: 19408, and: 5120, not: 640, mux: 0 }, fhe_aes.gates().counts());
+    }
+
+    #[test]
+    fn generic_aes_boolean_ciphertext() {
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let (client_key, server_key) = gen_keys();
+        let encrypt_byte = |x: u8| -> Vec<Ciphertext> { bits_from_u8(x).iter().map(|y| client_key.encrypt(*y)).collect() };
+        let fhe_aes = GenericAes::new(server_key, expanded_key.iter().map(|x| encrypt_byte(*x)).collect()).unwrap();
+        let block: Vec<Vec<Ciphertext>> = (0..16u8).map(encrypt_byte).collect();
+        let encrypted: Vec<u8> = fhe_aes.encrypt_block(block).unwrap().iter()
+            .map(|x| u8_from_bits(&x.iter().map(|y| client_key.decrypt(y)).collect::<Vec<bool>>()))
+            .collect();
+        assert_eq!(BLOCK_EXPECTED.to_vec(), encrypted);
+    }
+
+    #[test]
+    fn fhaes_error_test() {
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let round_keys: Vec<Vec<bool>> = expanded_key.iter().map(|x| bits_from_u8(*x)).collect();
+        let short_key = GenericAes::new(ClearGates, round_keys[..175].to_vec());
+        assert!(matches!(short_key, Err(FhaesError::InvalidLength { expected: 176, found: 175, .. })));
+
+        let fhe_aes = GenericAes::new(ClearGates, round_keys).unwrap();
+        let iv: Vec<Vec<bool>> = (0..16u8).map(bits_from_u8).collect();
+        assert!(matches!(fhe_aes.generate_keystream(&iv, 250..257), Err(FhaesError::CounterOverflow { blocks: 257 })));
+        assert!(matches!(fhe_aes.generate_keystream(&iv[..15].to_vec(), 0..1), Err(FhaesError::InvalidLength { expected: 16, found: 15, .. })));
+        assert!(matches!(fhe_aes.encrypt_block(vec![bits_from_u8(0)[..7].to_vec(); 16]), Err(FhaesError::InvalidLength { expected: 8, found: 7, .. })));
+
+        let keystream = fhe_aes.generate_keystream(&iv, 255..256).unwrap();
+        assert!(matches!(fhe_aes.apply_keystream(&vec![iv.clone(); 2], &keystream), Err(FhaesError::InvalidLength { expected: 2, found: 1, .. })));
+        assert_eq!(1, fhe_aes.apply_keystream(&vec![iv], &keystream).unwrap().len());
+    }
+
+    #[test]
+    fn ctr_counter_edges() {
+        let reference = ReferenceAes::from_key(&[0u8; 16]);
+        let expanded_key = key_expansion(&[0u8; 16]);
+        let fhe_aes = GenericAes::new(ClearGates, expanded_key.iter().map(|x| bits_from_u8(*x)).collect()).unwrap();
+        let iv: Vec<Vec<bool>> = CTR_IV.iter().map(|x| bits_from_u8(*x)).collect();
+
+        // the keystream is the encryption of zero blocks, every one of the MAX_CTR_BLOCKS counters is usable
+        assert!(check_counter(MAX_CTR_BLOCKS).is_ok());
+        let keystream = reference.encrypt_ctr_mode(vec![[0u8; 16]; MAX_CTR_BLOCKS], CTR_IV).unwrap();
+        let block = test_block();
+        let known: Vec<[u8; 16]> = keystream[..2].iter().map(|x| array::from_fn(|i| x[i]^block[i])).collect();
+        assert_eq!(CTR_EXPECTED.to_vec(), known);
+        assert!(matches!(reference.encrypt_ctr_mode(vec![[0u8; 16]; MAX_CTR_BLOCKS + 1], CTR_IV), Err(FhaesError::CounterOverflow { blocks: 257 })));
+        // counter 16 carries into iv[14] instead of wrapping around to counter 0
+        assert_ne!(keystream[0], keystream[16]);
+
+        // ranges across the carry and up to the last counter
+        for range in [14..18, 15..17, 16..17, 254..256] {
+            let decoded: Vec<[u8; 16]> = fhe_aes.generate_keystream(&iv, range.clone()).unwrap().iter()
+                .map(|x| array::from_fn(|i| u8_from_bits(&x[i])))
+                .collect();
+            assert_eq!(keystream[range], decoded[..]);
+        }
+        assert!(matches!(fhe_aes.generate_keystream(&iv, 255..257), Err(FhaesError::CounterOverflow { blocks: 257 })));
+    }
+
+    #[test]
+    fn aes_boolean_rejects_other_parameter_set() {
+        let key = [0u8; 16];
+        let expanded_key = key_expansion(&key);
+        let (client_key, server_key) = ParameterSet::TfheLib.gen_keys();
+        let fhe_key_aes = expanded_key.map(|x| byte_from_u8(&client_key, x));
+        // new checks against DEFAULT_PARAMETERS
+        assert!(matches!(AesBoolean::new(fhe_key_aes.clone(), server_key.clone()), Err(FhaesError::ParameterMismatch(_))));
+        // the server key itself is checked, a public key schedule fits any parameter set
+        let public_key = expanded_key.map(AesByte::from_clear);
+        assert!(matches!(AesBoolean::new(public_key.clone(), server_key.clone()), Err(FhaesError::ParameterMismatch(_))));
+        assert!(AesBoolean::builder(server_key.clone())
+            .with_key_schedule(public_key)
+            .with_parameters(ParameterSet::TfheLib.boolean_parameters())
+            .build()
+            .is_ok());
+        let fhe_aes = AesBoolean::builder(server_key.clone())
+            .with_key_schedule(fhe_key_aes.clone())
+            .with_parameters(ParameterSet::TfheLib.boolean_parameters())
+            .build()
+            .unwrap();
+        assert_eq!(fhe_aes.parameters(), ParameterSet::TfheLib.boolean_parameters());
+
+        let (other_client_key, _) = ParameterSet::ErrorProb2Pow165KsPbs.gen_keys();
+        let block: Vec<AesByte> = (0..16u8).map(|x| byte_from_u8(&other_client_key, x)).collect();
+        assert!(check_parameters(&fhe_aes.parameters(), &block).is_err());
+        assert!(fhe_aes
+            .with_parameters(ParameterSet::ErrorProb2Pow165KsPbs.boolean_parameters())
+            .is_err());
+
+        // same LWE size, only the whole parameter set tells them apart
+        let parameters = ParameterSet::Default.boolean_parameters();
+        let mut other = parameters;
+        other.pbs_level = DecompositionLevelCount(parameters.pbs_level.0 + 1);
+        assert!(check_parameter_set(&parameters, &parameters).is_ok());
+        assert!(matches!(check_parameter_set(&parameters, &other), Err(ParameterMismatch { field: "pbs_level", .. })));
+    }
+
+    #[test]
+    fn execution_config_test() {
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+        let config = ExecutionConfig::default()
+            .with_thread_pool(pool)
+            .with_threshold(Stage::Bits, 0)
+            .with_threshold(Stage::Blocks, usize::MAX);
+        assert_eq!(config.threshold(Stage::Bits), 0);
+        assert_eq!(config.threshold(Stage::Bytes), 2);
+        let squares = config.install(|| config.map(Stage::Bits, 8, |i| i*i));
+        assert_eq!(squares, vec![0, 1, 4, 9, 16, 25, 36, 49]);
+        let threads = config.install(|| rayon::current_num_threads());
+        assert_eq!(threads, 2);
     }
 }
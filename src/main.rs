@@ -1,21 +1,50 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use fhaes::fhaes::AES;
-use fhaes::fhaes_boolean::{AesBoolean, AesByte};
-use tfhe::{ConfigBuilder, generate_keys, set_server_key, FheUint8, CpuFheUint8Array};
-use tfhe::prelude::*;
-use aes::Aes128;
-use aes::cipher::{
-    BlockCipher, BlockEncrypt, BlockDecrypt, KeyInit,
-    generic_array::GenericArray,
-};
+use fhaes::fhaes_boolean::{AesBoolean, AesByte, CompressedAesByte, PagedRoundKeys};
+use tfhe::{ClientKey, ConfigBuilder, generate_keys, set_server_key};
 use rand::Rng;
 use hex;
-use fhaes::utils::{byte_from_u8, generate_counters, key_expansion};
-use rayon::prelude::*;
+use fhaes::blocks::{decrypt_blocks, encrypt_blocks};
+use fhaes::cipher::HomomorphicBlockCipher;
+use fhaes::error::FhaesError;
+use fhaes::fhaes_fhebool::AesFheBool;
+use fhaes::fhaes_shortint::AesShortint;
+use fhaes::fhaes_uint128::AesUint128;
+use fhaes::parameters::ParameterSet;
+use fhaes::progress::{Monitor, Progress};
+use fhaes::reference::ReferenceAes;
+use fhaes::shard::ShardCoordinator;
+use fhaes::utils::{byte_from_u8, compressed_byte_from_u8, decompress_bytes, generate_clear_counters, generate_compressed_counters, generate_counters, key_expansion};
 use std::time::{Duration, Instant};
-use tfhe::boolean::{gen_keys, prelude::ServerKey};
+use tfhe::boolean::prelude::ServerKey;
+use zeroize::Zeroizing;
 
-#[derive(Parser, Debug)]
+#[derive(ValueEnum, Clone, Debug)]
+enum Backend {
+    // fine grained boolean circuit
+    Boolean,
+    // high level FheUint8 with lookup tables
+    Integer,
+    // shortint nibbles, one lookup table per output nibble
+    Shortint,
+    // whole block FheUint128 state
+    Uint128,
+    // boolean circuit on high level FheBool
+    FheBool,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum RoundKeyStorage {
+    // all 176 encrypted key bytes in memory
+    Resident,
+    // only the 16 byte master key, round keys computed homomorphically
+    Streamed,
+    // round keys paged from a file
+    Paged,
+}
+
+// no Debug, the arguments hold the key
+#[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     //Initialisation vector 
@@ -28,32 +57,43 @@ struct Args {
 
     // AES key
     #[arg(short, long)]
-    key: String
-}
+    key: String,
 
-fn cleartext_aes(key: [u8; 16], input: [u8; 16]) -> [u8; 16] {
-    let key = GenericArray::from(key);
-    let mut input = GenericArray::from(input);
-    let cipher = Aes128::new(&key);
-    cipher.encrypt_block(&mut input);
-    return input.as_slice().try_into().unwrap();
-}
+    // FHE implementation to run
+    #[arg(short, long, value_enum, default_value_t = Backend::Boolean)]
+    backend: Backend,
 
-fn cleartext_ctr(key: [u8; 16], blocks: Vec<[u8; 16]>, mut iv: [u8; 16]) -> Vec<[u8; 16]> {
-    let num_blocks = blocks.len();
-    let mut results: Vec<[u8; 16]> = Vec::new();
-    for i in 0..num_blocks {
-        let c_n = cleartext_aes(key, iv);
-        let mut r_n = [0u8; 16];
-        for j in 0..16 {
-            r_n[j] = blocks[i][j]^c_n[j];
-        }
-        results.push(r_n);
-        let j = u8::try_from(i+1).unwrap();
-        iv[15] = j&0xf;
-        iv[14] = (j>>4)&0xf;
-    }
-    return results 
+    // Keep the IV in the clear (boolean backend only)
+    #[arg(long)]
+    public_iv: bool,
+
+    // Keep the plaintext blocks in the clear (boolean backend only)
+    #[arg(long)]
+    public_plaintext: bool,
+
+    // Number of local worker processes to shard the batch across (boolean backend only)
+    #[arg(long, default_value_t = 0)]
+    workers: usize,
+
+    // Number of blocks sent to a worker at a time
+    #[arg(long, default_value_t = 1)]
+    shard_size: usize,
+
+    // Where the encrypted round keys are kept (boolean backend only)
+    #[arg(long, value_enum, default_value_t = RoundKeyStorage::Resident)]
+    round_keys: RoundKeyStorage,
+
+    // TFHE parameter set (boolean backend only)
+    #[arg(long, value_enum, default_value_t = ParameterSet::Default)]
+    parameters: ParameterSet,
+
+    // Upload the encrypted inputs as seeded ciphertexts (boolean backend only)
+    #[arg(long)]
+    compressed_upload: bool,
+
+    // Print the rounds done and an estimate of the time left (not with --workers)
+    #[arg(long)]
+    progress: bool
 }
 
 fn generate_random_block() -> [u8; 16] {
@@ -62,79 +102,164 @@ fn generate_random_block() -> [u8; 16] {
     block 
 }
 
-// Uncomment this block to use High level API
-//fn main() {
-//    let args = Args::parse();
-//
-//    let config = ConfigBuilder::default().build();
-//    let (_, server_key) = generate_keys(config);
-//    rayon::broadcast(|_| set_server_key(server_key.clone()));
-//    set_server_key(server_key.clone());
-//
-//    let mut blocks: Vec<[u8; 16]> = Vec::new();
-//    for _ in 0..args.number_of_outputs {
-//        blocks.push(generate_random_block());
-//    }
-//
-//    let blocks_enc = blocks.iter().map(|x| x.map(|y| FheUint8::encrypt_trivial(y))).collect();
-//    let mut key = [0u8; 16];
-//    hex::decode_to_slice(args.key, &mut key).expect("Invalid key");
-//    let mut iv = [0u8; 16];
-//    hex::decode_to_slice(args.iv, &mut iv).expect("Invalid iv");
-//
-//    let iv_fhe = iv.map(|x| FheUint8::encrypt_trivial(x));
-//    let ke_instant = Instant::now();
-//    let expanded_key = key_expansion(&key);
-//    println!("AES key expansion took: {} ms", ke_instant.elapsed().as_millis());
-//
-//    let fhe_key_aes = expanded_key.map(|x| FheUint8::encrypt_trivial(x));
-//    let mut fhe_aes = AES::new(fhe_key_aes);
-//    fhe_aes.set_aes_key(expanded_key);
-//
-//    let cleartext_enc = cleartext_ctr(key.clone(), blocks, iv);
-//    let enc_instant = Instant::now();
-//    let fhe_enc = fhe_aes.encrypt_ctr_mode_fhe(blocks_enc, iv_fhe);
-//    println!("AES of {} outputs computed in : {} ms", args.number_of_outputs, enc_instant.elapsed().as_millis());
-//
-//    let fhe_enc: Vec<Vec<u8>> = fhe_enc.par_iter().map(|x| x.par_iter().map(|y| y.try_decrypt_trivial().unwrap()).collect()).collect();
-//    println!("cleartext enc: {:?}", cleartext_enc);
-//    println!("fhe enc: {:?}", fhe_enc);
-//}
-
-
-fn main() {
-    let args = Args::parse();
-
-    let (client_key, server_key) = gen_keys();
-    let mut blocks: Vec<[u8; 16]> = Vec::new();
-    for _ in 0..args.number_of_outputs {
-        blocks.push(generate_random_block());
+fn expand_key(key: &[u8; 16]) -> Zeroizing<[u8; 176]> {
+    let ke_instant = Instant::now();
+    let expanded_key = key_expansion(key);
+    println!("AES key expansion took: {} ms", ke_instant.elapsed().as_millis());
+    expanded_key
+}
+
+// with --progress the backends report every round on stderr
+fn monitor(args: &Args) -> Monitor {
+    if !args.progress {
+        return Monitor::new();
     }
+    Monitor::new().with_callback(|progress: Progress| {
+        let eta = progress.eta.map(|x| x.as_secs().to_string()).unwrap_or_else(|| String::from("?"));
+        eprintln!("round {}/{}, {} s elapsed, about {} s left", progress.rounds_done, progress.rounds_total, progress.elapsed.as_secs(), eta);
+    })
+}
 
-    let blocks_enc = blocks.iter().map(|x| x.iter().map(|y| byte_from_u8(&client_key, *y)).collect()).collect();
-    let mut key = [0u8; 16];
+// everything after the keys are set up is the same for every backend
+fn run_ctr<C: HomomorphicBlockCipher>(args: &Args, cipher: &C, client_key: &C::ClientKey, iv: [u8; 16], blocks: Vec<[u8; 16]>) -> Result<Vec<[u8; 16]>, FhaesError> {
+    let blocks_enc = encrypt_blocks::<C>(client_key, &blocks);
+    let iv_fhe = C::encrypt_input(client_key, &iv);
 
-    hex::decode_to_slice(args.key, &mut key).expect("Invalid key");
-    let mut iv = [0u8; 16];
-    hex::decode_to_slice(args.iv, &mut iv).expect("Invalid iv");
+    let enc_instant = Instant::now();
+    let fhe_enc = cipher.encrypt_ctr(blocks_enc, iv_fhe)?;
+    println!("AES of {} outputs computed in : {} ms", args.number_of_outputs, enc_instant.elapsed().as_millis());
 
-    let iv_fhe: Vec<AesByte> = iv.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
-    let ke_instant = Instant::now();
-    let expanded_key = key_expansion(&key);
-    println!("AES key expansion took: {} ms", ke_instant.elapsed().as_millis());
+    Ok(decrypt_blocks::<C>(client_key, &fhe_enc))
+}
+
+// keys of the high level api, shared by the integer, uint128 and fhe-bool backends
+fn high_level_keys() -> ClientKey {
+    let config = ConfigBuilder::default().build();
+    let (client_key, server_key) = generate_keys(config);
+    rayon::broadcast(|_| set_server_key(server_key.clone()));
+    set_server_key(server_key);
+    client_key
+}
+
+fn run_integer(args: &Args, key: &[u8; 16], iv: [u8; 16], blocks: Vec<[u8; 16]>) -> Result<Vec<[u8; 16]>, FhaesError> {
+    let client_key = high_level_keys();
+    let mut fhe_aes = AES::new(AES::encrypt_key(&client_key, &expand_key(key)))?;
+    fhe_aes.set_monitor(monitor(args));
+    run_ctr(args, &fhe_aes, &client_key, iv, blocks)
+}
 
-    let fhe_key_aes = expanded_key.map(|x| byte_from_u8(&client_key, x));
-    let t = client_key.encrypt(true);
-    let f = client_key.encrypt(false);
-    let mut fhe_aes = AesBoolean::new(fhe_key_aes, server_key, t, f);
-    let (counter1, counter2) = generate_counters(&client_key, 2);
+fn run_uint128(args: &Args, key: &[u8; 16], iv: [u8; 16], blocks: Vec<[u8; 16]>) -> Result<Vec<[u8; 16]>, FhaesError> {
+    let client_key = high_level_keys();
+    let mut fhe_aes = AesUint128::new(AesUint128::encrypt_key(&client_key, &expand_key(key)))?;
+    fhe_aes.set_monitor(monitor(args));
+    run_ctr(args, &fhe_aes, &client_key, iv, blocks)
+}
+
+fn run_fhebool(args: &Args, key: &[u8; 16], iv: [u8; 16], blocks: Vec<[u8; 16]>) -> Result<Vec<[u8; 16]>, FhaesError> {
+    let client_key = high_level_keys();
+    let mut fhe_aes = AesFheBool::new(AesFheBool::encrypt_key(&client_key, &expand_key(key)))?;
+    fhe_aes.set_monitor(monitor(args));
+    run_ctr(args, &fhe_aes, &client_key, iv, blocks)
+}
+
+fn run_shortint(args: &Args, key: &[u8; 16], iv: [u8; 16], blocks: Vec<[u8; 16]>) -> Result<Vec<[u8; 16]>, FhaesError> {
+    let (client_key, server_key) = tfhe::shortint::gen_keys(tfhe::shortint::parameters::PARAM_MESSAGE_4_CARRY_4_KS_PBS);
+    let mut fhe_aes = AesShortint::new(AesShortint::encrypt_key(&client_key, &expand_key(key)), server_key)?;
+    fhe_aes.set_monitor(monitor(args));
+    run_ctr(args, &fhe_aes, &client_key, iv, blocks)
+}
+
+fn run_boolean(args: &Args, key: &[u8; 16], iv: [u8; 16], blocks: Vec<[u8; 16]>) -> Result<Vec<[u8; 16]>, FhaesError> {
+    let (client_key, server_key) = args.parameters.gen_keys();
+    let parameters = args.parameters.boolean_parameters();
+
+    // client side encryption, with --compressed-upload the seeded ciphertexts are
+    // what goes over the wire and the server decompresses them
+    let encrypt = |input: &[u8]| -> Vec<AesByte> {
+        if args.compressed_upload {
+            let upload: Vec<CompressedAesByte> = input.iter().map(|x| compressed_byte_from_u8(&client_key, *x)).collect();
+            decompress_bytes(&upload)
+        } else {
+            input.iter().map(|x| byte_from_u8(&client_key, *x)).collect()
+        }
+    };
+    if args.compressed_upload {
+        let upload: Vec<CompressedAesByte> = blocks[0].iter().map(|x| compressed_byte_from_u8(&client_key, *x)).collect();
+        let compressed_size = bincode::serialized_size(&upload)?;
+        let full_size = bincode::serialized_size(&decompress_bytes(&upload))?;
+        println!("Upload per block: {} bytes compressed instead of {} bytes ({} bytes saved)", compressed_size, full_size, full_size - compressed_size);
+    }
+
+    let blocks_enc = if args.public_plaintext {
+        blocks.iter().map(|x| x.iter().map(|y| AesByte::from_clear(*y)).collect()).collect()
+    } else {
+        blocks.iter().map(|x| encrypt(&x[..])).collect()
+    };
+    let iv_fhe: Vec<AesByte> = if args.public_iv {
+        iv.iter().map(|x| AesByte::from_clear(*x)).collect()
+    } else {
+        encrypt(&iv[..])
+    };
+    let expanded_key = expand_key(key);
+    let fhe_key_aes: [AesByte; 176] = encrypt(&expanded_key[..]).try_into()
+        .map_err(|x: Vec<AesByte>| FhaesError::InvalidLength { what: "expanded key bytes", expected: 176, found: x.len() })?;
+    if args.workers > 0 {
+        let worker_exe = std::env::current_exe()?.with_file_name("fhaes_worker");
+        let mut coordinator = ShardCoordinator::spawn_local(&worker_exe, args.workers, &server_key, &fhe_key_aes, parameters, args.shard_size)?;
+        let enc_instant = Instant::now();
+        let fhe_enc = coordinator.encrypt_ctr_mode_fhe(blocks_enc, iv_fhe)?;
+        println!("AES of {} outputs computed by {} workers in : {} ms", args.number_of_outputs, args.workers, enc_instant.elapsed().as_millis());
+        return Ok(decrypt_blocks::<AesBoolean>(&client_key, &fhe_enc));
+    }
+
+    // the server only needs its server key and the encrypted key
+    let builder = AesBoolean::builder(server_key).with_parameters(parameters).with_monitor(monitor(args));
+    let fhe_aes = match args.round_keys {
+        RoundKeyStorage::Resident => builder.with_key_schedule(fhe_key_aes),
+        RoundKeyStorage::Streamed => builder.with_master_key(encrypt(&key[..])),
+        RoundKeyStorage::Paged => builder.with_key_schedule(fhe_key_aes).with_paged_round_keys(PagedRoundKeys::temp_path())
+    }.build()?;
+    let (counter1, counter2) = if args.public_iv {
+        generate_clear_counters(u16::from(args.number_of_outputs))
+    } else if args.compressed_upload {
+        let (counter1, counter2) = generate_compressed_counters(&client_key, u16::from(args.number_of_outputs));
+        (decompress_bytes(&counter1), decompress_bytes(&counter2))
+    } else {
+        generate_counters(&client_key, u16::from(args.number_of_outputs))
+    };
 
-    let cleartext_enc = cleartext_ctr(key.clone(), blocks, iv);
     let enc_instant = Instant::now();
-    let fhe_enc = fhe_aes.encrypt_ctr_mode_fhe(blocks_enc, iv_fhe, counter1, counter2);
+    let fhe_enc = fhe_aes.encrypt_ctr_mode_fhe(blocks_enc, iv_fhe, counter1, counter2)?;
     println!("AES of {} outputs computed in : {} ms", args.number_of_outputs, enc_instant.elapsed().as_millis());
 
-    let fhe_enc: Vec<Vec<u8>> = fhe_enc.par_iter().map(|x| x.par_iter().map(|y| y.decrypt(&client_key)).collect()).collect();
+    Ok(decrypt_blocks::<AesBoolean>(&client_key, &fhe_enc))
+}
+
+fn main() -> Result<(), FhaesError> {
+    let mut args = Args::parse();
+
+    let mut blocks: Vec<[u8; 16]> = Vec::new();
+    for _ in 0..args.number_of_outputs {
+        blocks.push(generate_random_block());
+    }
+
+    // the key is moved out of the arguments and wiped with the parsed bytes at the end of the run,
+    // a parse error does not echo it
+    let key_hex = Zeroizing::new(std::mem::take(&mut args.key));
+    let mut key = Zeroizing::new([0u8; 16]);
+    hex::decode_to_slice(&*key_hex, &mut *key).map_err(|_| FhaesError::InvalidKey)?;
+    let mut iv = [0u8; 16];
+    hex::decode_to_slice(&args.iv, &mut iv)?;
+
+    let cleartext_enc = ReferenceAes::from_key(&key).encrypt_ctr_mode(blocks.clone(), iv)?;
+    let fhe_enc = match args.backend {
+        Backend::Boolean => run_boolean(&args, &key, iv, blocks),
+        Backend::Integer => run_integer(&args, &key, iv, blocks),
+        Backend::Shortint => run_shortint(&args, &key, iv, blocks),
+        Backend::Uint128 => run_uint128(&args, &key, iv, blocks),
+        Backend::FheBool => run_fhebool(&args, &key, iv, blocks),
+    }?;
     println!("cleartext enc: {:?}", cleartext_enc);
     println!("fhe enc: {:?}", fhe_enc);
+    Ok(())
 }
@@ -0,0 +1,142 @@
+use tfhe::boolean::prelude::*;
+use tfhe::core_crypto::prelude::{CiphertextModulus, EncryptionKeyChoice, FourierLweBootstrapKeyOwned, LweKeyswitchKeyOwned, LweSize};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::fhaes_boolean::{AesBit, AesByte};
+
+// The tfhe boolean parameter sets that AesBoolean can run with
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParameterSet {
+    // DEFAULT_PARAMETERS, the ones used by tfhe::boolean::gen_keys
+    Default,
+    // DEFAULT_PARAMETERS_KS_PBS
+    DefaultKsPbs,
+    // TFHE_LIB_PARAMETERS
+    TfheLib,
+    // PARAMETERS_ERROR_PROB_2_POW_MINUS_165
+    ErrorProb2Pow165,
+    // PARAMETERS_ERROR_PROB_2_POW_MINUS_165_KS_PBS
+    ErrorProb2Pow165KsPbs,
+}
+
+impl ParameterSet {
+    pub fn boolean_parameters(self: &ParameterSet) -> BooleanParameters {
+        match self {
+            ParameterSet::Default => DEFAULT_PARAMETERS,
+            ParameterSet::DefaultKsPbs => DEFAULT_PARAMETERS_KS_PBS,
+            ParameterSet::TfheLib => TFHE_LIB_PARAMETERS,
+            ParameterSet::ErrorProb2Pow165 => PARAMETERS_ERROR_PROB_2_POW_MINUS_165,
+            ParameterSet::ErrorProb2Pow165KsPbs => PARAMETERS_ERROR_PROB_2_POW_MINUS_165_KS_PBS,
+        }
+    }
+
+    // same as tfhe::boolean::gen_keys but for the chosen parameter set
+    pub fn gen_keys(self: &ParameterSet) -> (ClientKey, ServerKey) {
+        let client_key = ClientKey::new(&self.boolean_parameters());
+        let server_key = ServerKey::new(&client_key);
+        (client_key, server_key)
+    }
+}
+
+// Size of the LWE ciphertexts that are fed to the gates under `parameters`
+pub fn ciphertext_lwe_size(parameters: &BooleanParameters) -> LweSize {
+    match parameters.encryption_key_choice {
+        EncryptionKeyChoice::Big => parameters.glwe_dimension.to_equivalent_lwe_dimension(parameters.polynomial_size).to_lwe_size(),
+        EncryptionKeyChoice::Small => parameters.lwe_dimension.to_lwe_size()
+    }
+}
+
+// An operand or a stored parameter set does not match the parameter set of the server key,
+// `field` names the first parameter that differs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterMismatch {
+    pub field       : &'static str,
+    pub expected    : String,
+    pub found       : String
+}
+
+impl ParameterMismatch {
+    fn new<T: fmt::Debug>(field: &'static str, expected: T, found: T) -> ParameterMismatch {
+        ParameterMismatch {
+            field       : field,
+            expected    : format!("{:?}", expected),
+            found       : format!("{:?}", found)
+        }
+    }
+}
+
+impl fmt::Display for ParameterMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} does not match the parameter set ({})", self.field, self.found, self.expected)
+    }
+}
+
+impl std::error::Error for ParameterMismatch {}
+
+fn check_field<T: PartialEq + fmt::Debug>(field: &'static str, expected: T, found: T) -> Result<(), ParameterMismatch> {
+    if expected != found {
+        return Err(ParameterMismatch::new(field, expected, found));
+    }
+    Ok(())
+}
+
+// Compares two whole parameter sets, every dimension, noise distribution and
+// decomposition, e.g. the one stored with a keystream or paged round keys
+pub fn check_parameter_set(expected: &BooleanParameters, found: &BooleanParameters) -> Result<(), ParameterMismatch> {
+    check_field("lwe_dimension", expected.lwe_dimension, found.lwe_dimension)?;
+    check_field("glwe_dimension", expected.glwe_dimension, found.glwe_dimension)?;
+    check_field("polynomial_size", expected.polynomial_size, found.polynomial_size)?;
+    check_field("lwe_noise_distribution", expected.lwe_noise_distribution, found.lwe_noise_distribution)?;
+    check_field("glwe_noise_distribution", expected.glwe_noise_distribution, found.glwe_noise_distribution)?;
+    check_field("pbs_base_log", expected.pbs_base_log, found.pbs_base_log)?;
+    check_field("pbs_level", expected.pbs_level, found.pbs_level)?;
+    check_field("ks_base_log", expected.ks_base_log, found.ks_base_log)?;
+    check_field("ks_level", expected.ks_level, found.ks_level)?;
+    check_field("encryption_key_choice", expected.encryption_key_choice, found.encryption_key_choice)?;
+    Ok(())
+}
+
+// The dimensions and decompositions of the bootstrapping and key switching keys.
+// The key switching key maps the big (GLWE) key to the small LWE key in both PBS orders.
+fn check_key_parts(parameters: &BooleanParameters, bootstrapping_key: &FourierLweBootstrapKeyOwned, key_switching_key: &LweKeyswitchKeyOwned) -> Result<(), ParameterMismatch> {
+    let big_lwe_dimension = parameters.glwe_dimension.to_equivalent_lwe_dimension(parameters.polynomial_size);
+    check_field("key switching input LWE dimension", big_lwe_dimension, key_switching_key.input_key_lwe_dimension())?;
+    check_field("key switching output LWE dimension", parameters.lwe_dimension, key_switching_key.output_key_lwe_dimension())?;
+    check_field("key switching modulus", CiphertextModulus::new_native(), key_switching_key.ciphertext_modulus())?;
+    check_field("ks_base_log", parameters.ks_base_log, key_switching_key.decomposition_base_log())?;
+    check_field("ks_level", parameters.ks_level, key_switching_key.decomposition_level_count())?;
+    check_field("bootstrapping input LWE dimension", parameters.lwe_dimension, bootstrapping_key.input_lwe_dimension())?;
+    check_field("glwe_dimension", parameters.glwe_dimension.to_glwe_size(), bootstrapping_key.glwe_size())?;
+    check_field("polynomial_size", parameters.polynomial_size, bootstrapping_key.polynomial_size())?;
+    check_field("pbs_base_log", parameters.pbs_base_log, bootstrapping_key.decomposition_base_log())?;
+    check_field("pbs_level", parameters.pbs_level, bootstrapping_key.decomposition_level_count())?;
+    Ok(())
+}
+
+// Checks that `server_key` was generated under `parameters`, a key of another set
+// would make tfhe panic on the first gate. The key is taken apart to read its
+// dimensions and handed back whole.
+pub fn check_server_key(parameters: &BooleanParameters, server_key: ServerKey) -> Result<ServerKey, ParameterMismatch> {
+    let (bootstrapping_key, key_switching_key, pbs_order) = server_key.into_raw_parts();
+    check_key_parts(parameters, &bootstrapping_key, &key_switching_key)?;
+    Ok(ServerKey::from_raw_parts(bootstrapping_key, key_switching_key, pbs_order))
+}
+
+// Checks every encrypted bit of `bytes`, clear and trivial bits fit any parameter set.
+// A ciphertext only carries its LWE size and modulus, the noise and decomposition
+// parameters are checked with check_parameter_set where the parameter set is stored
+// next to the data.
+pub fn check_parameters(parameters: &BooleanParameters, bytes: &[AesByte]) -> Result<(), ParameterMismatch> {
+    let expected = ciphertext_lwe_size(parameters);
+    for byte in bytes {
+        for bit in byte.as_bits() {
+            if let AesBit::Encrypted(Ciphertext::Encrypted(ct)) = bit {
+                check_field("ciphertext LWE size", expected, ct.lwe_size())?;
+                check_field("ciphertext modulus", CiphertextModulus::new_native(), ct.ciphertext_modulus())?;
+            }
+        }
+    }
+    Ok(())
+}
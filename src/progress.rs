@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::error::FhaesError;
+
+// A snapshot of the work of a Monitor, counted in AES rounds of a block
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub rounds_done     : usize,
+    pub rounds_total    : usize,
+    pub elapsed         : Duration,
+    // extrapolated from the rounds done so far, None before the first one
+    pub eta             : Option<Duration>,
+}
+
+// Shared flag to stop an evaluation from another thread, e.g. a job runner.
+// Clones refer to the same flag.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled       : Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // clears the flag so that the token can be used for the next run
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
+
+pub type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+#[derive(Default)]
+struct MonitorState {
+    done            : AtomicUsize,
+    total           : AtomicUsize,
+    // calls between add_work and the drop of their MonitorRun
+    active          : AtomicUsize,
+    start           : Mutex<Option<Instant>>,
+}
+
+// Progress reporting and cooperative cancellation of the rounds evaluated by a
+// cipher. The modes announce their rounds with add_work before they start, the
+// cancellation token is checked before every round and the callback runs after
+// every round, from whichever thread finished it. Work announced while no other
+// call is running starts a new run with fresh counters, so the rounds left over
+// by a cancelled or failed call do not count, the counters of concurrent calls add up.
+#[derive(Clone, Default)]
+pub struct Monitor {
+    callback        : Option<ProgressCallback>,
+    token           : CancellationToken,
+    state           : Arc<MonitorState>,
+}
+
+impl Monitor {
+
+    // initialise, no callback and a token nobody cancels
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    pub fn with_callback<F: Fn(Progress) + Send + Sync + 'static>(mut self, callback: F) -> Monitor {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Monitor {
+        self.token = token;
+        self
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    // the call is part of the run until the returned MonitorRun is dropped
+    pub fn add_work(&self, rounds: usize) -> MonitorRun<'_> {
+        let mut start = self.state.start.lock().unwrap();
+        if self.state.active.load(Ordering::Relaxed) == 0 {
+            *start = Some(Instant::now());
+            self.state.done.store(0, Ordering::Relaxed);
+            self.state.total.store(0, Ordering::Relaxed);
+        }
+        self.state.active.fetch_add(1, Ordering::Relaxed);
+        self.state.total.fetch_add(rounds, Ordering::Relaxed);
+        MonitorRun {
+            monitor         : self,
+        }
+    }
+
+    // called before every round
+    pub fn check(&self) -> Result<(), FhaesError> {
+        if self.token.is_cancelled() {
+            return Err(FhaesError::Cancelled);
+        }
+        Ok(())
+    }
+
+    // called after every round
+    pub fn round_done(&self) {
+        self.state.done.fetch_add(1, Ordering::Relaxed);
+        if let Some(callback) = &self.callback {
+            callback(self.progress());
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        let elapsed = match *self.state.start.lock().unwrap() {
+            Some(start) => start.elapsed(),
+            None => Duration::ZERO
+        };
+        let rounds_done = self.state.done.load(Ordering::Relaxed);
+        let rounds_total = self.state.total.load(Ordering::Relaxed);
+        let eta = if rounds_done > 0 {
+            Some(elapsed.mul_f64(rounds_total.saturating_sub(rounds_done) as f64 / rounds_done as f64))
+        } else {
+            None
+        };
+        Progress {
+            rounds_done     : rounds_done,
+            rounds_total    : rounds_total,
+            elapsed         : elapsed,
+            eta             : eta,
+        }
+    }
+}
+
+// One call's share of a run of a Monitor, returned by add_work. Hold it until
+// the call returns, whether it finished, failed or was cancelled.
+#[must_use = "the run ends when the MonitorRun is dropped"]
+pub struct MonitorRun<'a> {
+    monitor         : &'a Monitor,
+}
+
+impl Drop for MonitorRun<'_> {
+    fn drop(&mut self) {
+        let _start = self.monitor.state.start.lock().unwrap();
+        self.monitor.state.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
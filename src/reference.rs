@@ -0,0 +1,221 @@
+use aes::cipher::consts::U16;
+use aes::cipher::{BlockCipher, Key, KeyInit, KeySizeUser};
+use crate::error::{check_counter, FhaesError};
+use crate::utils::key_expansion;
+use zeroize::Zeroizing;
+
+// The AES SBox and its inverse, the only copies in the crate: the key schedule
+// in utils, the lookup tables of the FheUint8 backend and ReferenceAes all read them
+pub const SBOX: [u8; 256] = [
+    // 0     1    2      3     4    5     6     7      8    9     A      B    C     D     E     F
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,  // 0
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,  // 1
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,  // 2
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,  // 3
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,  // 4
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,  // 5
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,  // 6
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,  // 7
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,  // 8
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,  // 9
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,  // A
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,  // B
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,  // C
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,  // D
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,  // E
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16];  // F
+
+pub const INV_SBOX: [u8; 256] = [
+    // 0     1    2      3     4    5     6     7      8    9     A      B    C     D     E     F
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,  // 0
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,  // 1
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,  // 2
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,  // 3
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,  // 4
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,  // 5
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,  // 6
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,  // 7
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,  // 8
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,  // 9
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,  // A
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,  // B
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,  // C
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,  // D
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,  // E
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d];  // F
+
+// multiplication by x in GF(2^8)
+pub fn xtime(x: u8) -> u8 {
+    let h = x & 0x80;
+    let mut b = x << 1;
+    if h == 0x80 {
+        b ^= 0x1B;
+    }
+    b
+}
+
+// multiplication in GF(2^8), InvMixColumns needs more than xtime
+fn gf_mul(a: u8, b: u8) -> u8 {
+    let mut result = 0u8;
+    let mut a = a;
+    for i in 0..8 {
+        if (b >> i) & 1 == 1 {
+            result ^= a;
+        }
+        a = xtime(a);
+    }
+    result
+}
+
+fn sub_bytes(block: &[u8; 16], table: &[u8; 256]) -> [u8; 16] {
+    block.map(|x| table[usize::from(x)])
+}
+
+// row r moves r columns to the left
+fn shift_rows(block: &[u8; 16]) -> [u8; 16] {
+    std::array::from_fn(|i| block[(i + 4*(i%4)) % 16])
+}
+
+fn inv_shift_rows(block: &[u8; 16]) -> [u8; 16] {
+    std::array::from_fn(|i| block[(i + 16 - 4*(i%4)) % 16])
+}
+
+// row k of a column is sum_j coefficients[j]*a_(k+j), [2, 3, 1, 1] for MixColumns
+// and [14, 11, 13, 9] for its inverse
+fn mix_columns_with(block: &[u8; 16], coefficients: [u8; 4]) -> [u8; 16] {
+    std::array::from_fn(|i| {
+        let (column, row) = (i/4, i%4);
+        let mut result = 0u8;
+        for j in 0..4 {
+            result ^= gf_mul(block[4*column + (row+j)%4], coefficients[j]);
+        }
+        result
+    })
+}
+
+// Cleartext AES-128 on the tables and key schedule above. It implements the
+// RustCrypto KeyInit/BlockEncrypt/BlockDecrypt traits, so it can stand in for
+// aes::Aes128, and it is the oracle the FHE backends are checked against.
+// The round keys are wiped on drop and there is no Debug, so they cannot end up in logs.
+#[derive(Clone)]
+pub struct ReferenceAes {
+    round_keys      : Zeroizing<[u8; 176]>,
+}
+
+impl ReferenceAes {
+
+    // initialise
+    pub fn from_key(key: &[u8; 16]) -> ReferenceAes {
+        ReferenceAes {
+            round_keys      : key_expansion(key),
+        }
+    }
+
+    //(note this is not the 128 bit key, its the key after the offline key_expansion step)
+    pub fn from_expanded_key(round_keys: &[u8; 176]) -> ReferenceAes {
+        ReferenceAes {
+            round_keys      : Zeroizing::new(*round_keys),
+        }
+    }
+
+    pub fn expanded_key(&self) -> &[u8; 176] {
+        &self.round_keys
+    }
+
+    pub fn encrypt_block_bytes(&self, mut block: [u8; 16]) -> [u8; 16] {
+        block = self.add_round_key(&block, 0);
+        for t in 0..9 {
+            block = sub_bytes(&block, &SBOX);
+            block = shift_rows(&block);
+            block = mix_columns_with(&block, [2, 3, 1, 1]);
+            block = self.add_round_key(&block, t+1);
+        }
+        block = sub_bytes(&block, &SBOX);
+        block = shift_rows(&block);
+        self.add_round_key(&block, 10)
+    }
+
+    pub fn decrypt_block_bytes(&self, mut block: [u8; 16]) -> [u8; 16] {
+        block = self.add_round_key(&block, 10);
+        block = inv_shift_rows(&block);
+        block = sub_bytes(&block, &INV_SBOX);
+        for t in (1..10).rev() {
+            block = self.add_round_key(&block, t);
+            block = mix_columns_with(&block, [14, 11, 13, 9]);
+            block = inv_shift_rows(&block);
+            block = sub_bytes(&block, &INV_SBOX);
+        }
+        self.add_round_key(&block, 0)
+    }
+
+    // same counter layout as the ctr modes of the FHE backends
+    pub fn encrypt_ctr_mode(&self, blocks: Vec<[u8; 16]>, mut iv: [u8; 16]) -> Result<Vec<[u8; 16]>, FhaesError> {
+        let num_blocks = blocks.len();
+        check_counter(num_blocks)?;
+        let mut results: Vec<[u8; 16]> = Vec::new();
+        for i in 0..num_blocks {
+            iv[15] = u8::try_from(i&0xf).unwrap();
+            iv[14] = u8::try_from((i>>4)&0xf).unwrap();
+            let c_n = self.encrypt_block_bytes(iv);
+            let mut r_n = [0u8; 16];
+            for j in 0..16 {
+                r_n[j] = blocks[i][j]^c_n[j];
+            }
+            results.push(r_n);
+        }
+        Ok(results)
+    }
+
+    // CTR over a message of any length, the last block uses only the keystream bytes it needs
+    pub fn encrypt_ctr_bytes(&self, message: &[u8], iv: [u8; 16]) -> Result<Vec<u8>, FhaesError> {
+        self.encrypt_ctr_range(message, iv, 0)
+    }
+
+    // CTR of `message` as the bytes at `offset` of a longer stream
+    pub fn encrypt_ctr_range(&self, message: &[u8], mut iv: [u8; 16], offset: usize) -> Result<Vec<u8>, FhaesError> {
+        let end = offset + message.len();
+        check_counter(end.div_ceil(16))?;
+        let mut results: Vec<u8> = Vec::new();
+        for i in offset/16..end.div_ceil(16) {
+            iv[15] = u8::try_from(i&0xf).unwrap();
+            iv[14] = u8::try_from((i>>4)&0xf).unwrap();
+            let c_n = self.encrypt_block_bytes(iv);
+            for position in usize::max(offset, 16*i)..usize::min(end, 16*(i+1)) {
+                results.push(message[position - offset]^c_n[position % 16]);
+            }
+        }
+        Ok(results)
+    }
+
+    fn add_round_key(&self, block: &[u8; 16], round_no: usize) -> [u8; 16] {
+        std::array::from_fn(|i| block[i]^self.round_keys[16*round_no+i])
+    }
+}
+
+impl KeySizeUser for ReferenceAes {
+    type KeySize = U16;
+}
+
+impl KeyInit for ReferenceAes {
+    fn new(key: &Key<ReferenceAes>) -> ReferenceAes {
+        let mut bytes = Zeroizing::new([0u8; 16]);
+        bytes.copy_from_slice(key);
+        ReferenceAes::from_key(&bytes)
+    }
+}
+
+impl BlockCipher for ReferenceAes {}
+
+aes::cipher::impl_simple_block_encdec!(
+    ReferenceAes, U16, reference, block,
+    encrypt: {
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(block.get_in());
+        block.get_out().copy_from_slice(&reference.encrypt_block_bytes(bytes));
+    }
+    decrypt: {
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(block.get_in());
+        block.get_out().copy_from_slice(&reference.decrypt_block_bytes(bytes));
+    }
+);
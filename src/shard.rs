@@ -0,0 +1,314 @@
+use tfhe::boolean::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::cipher::AesRounds;
+use crate::error::{check_counter, FhaesError};
+use crate::fhaes_boolean::{check_block, AesBoolean, AesByte};
+use crate::parameters::check_parameter_set;
+
+// Messages from the coordinator to a worker. The server key and the encrypted
+// round keys travel once in Setup, every Encrypt afterwards only carries a shard.
+#[derive(Serialize, Deserialize)]
+pub enum ShardRequest {
+    Setup { server_key: ServerKey, aes_key: Vec<AesByte>, parameters: BooleanParameters },
+    Encrypt { shard_id: usize, iv: Vec<AesByte>, first_block: usize, blocks: Vec<Vec<AesByte>> },
+    Shutdown
+}
+
+// ShardRequest::Setup with borrowed keys. Setup is the first variant of both
+// enums and bincode writes a slice like a Vec, so the encoding is the same and
+// the coordinator does not have to clone the server key to send it.
+#[derive(Serialize)]
+enum ShardSetupRef<'a> {
+    Setup { server_key: &'a ServerKey, aes_key: &'a [AesByte], parameters: BooleanParameters }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ShardResponse {
+    // the parameter set the worker built its AesBoolean with
+    Ready { parameters: BooleanParameters },
+    Encrypted { shard_id: usize, blocks: Vec<Vec<AesByte>> },
+    // the worker could not handle the request but is still serving, the
+    // coordinator reports it instead of retrying the shard on another worker
+    Error(String)
+}
+
+// the wait for Ready, the worker deserializes the server key first
+const SETUP_TIMEOUT: Duration = Duration::from_secs(120);
+// a peer that does not take a message within this time is treated as dead
+const WRITE_TIMEOUT: Duration = Duration::from_secs(120);
+// the default wait for the result of a shard, see ShardCoordinator::set_timeout
+pub const DEFAULT_SHARD_TIMEOUT: Duration = Duration::from_secs(3600);
+
+fn invalid_data(msg: &str) -> FhaesError {
+    FhaesError::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn send<T: Serialize>(stream: &TcpStream, msg: &T) -> Result<(), FhaesError> {
+    let mut writer = BufWriter::new(stream);
+    bincode::serialize_into(&mut writer, msg)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn receive<T: DeserializeOwned>(reader: &mut BufReader<TcpStream>) -> Result<T, FhaesError> {
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+fn encrypt_shard(aes: Option<&AesBoolean>, iv: &Vec<AesByte>, first_block: usize, blocks: &Vec<Vec<AesByte>>) -> Result<Vec<Vec<AesByte>>, FhaesError> {
+    let aes = aes.ok_or(invalid_data("Encrypt received before Setup"))?;
+    let keystream = aes.generate_keystream(iv, first_block..first_block+blocks.len())?;
+    aes.apply_keystream(blocks, &keystream)
+}
+
+// Serves a single coordinator on `listener` until it sends Shutdown or disconnects.
+pub fn serve_worker(listener: TcpListener) -> Result<(), FhaesError> {
+    serve_worker_with(listener, |_| ())
+}
+
+// serve_worker, `on_shard` is called with the id of every shard before it is evaluated
+pub fn serve_worker_with<F: FnMut(usize)>(listener: TcpListener, mut on_shard: F) -> Result<(), FhaesError> {
+    let (stream, _) = listener.accept()?;
+    // no read timeout, the coordinator may stay idle between batches
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut aes: Option<AesBoolean> = None;
+    loop {
+        let request: ShardRequest = match receive(&mut reader) {
+            Ok(request) => request,
+            // coordinator went away
+            Err(_) => return Ok(())
+        };
+        // errors are sent back, the worker keeps serving
+        let response = match request {
+            ShardRequest::Setup { server_key, aes_key, parameters } => {
+                // everything needed is in the message, the key schedule is validated by the builder
+                let aes_boolean = AesBoolean::builder(server_key)
+                    .with_key_schedule(aes_key)
+                    .with_parameters(parameters)
+                    .build();
+                match aes_boolean {
+                    Ok(aes_boolean) => {
+                        let response = ShardResponse::Ready { parameters: aes_boolean.parameters() };
+                        aes = Some(aes_boolean);
+                        response
+                    },
+                    Err(e) => ShardResponse::Error(e.to_string())
+                }
+            },
+            ShardRequest::Encrypt { shard_id, iv, first_block, blocks } => {
+                on_shard(shard_id);
+                match encrypt_shard(aes.as_ref(), &iv, first_block, &blocks) {
+                    Ok(blocks) => ShardResponse::Encrypted { shard_id: shard_id, blocks: blocks },
+                    Err(e) => ShardResponse::Error(e.to_string())
+                }
+            },
+            ShardRequest::Shutdown => return Ok(())
+        };
+        send(&stream, &response)?;
+    }
+}
+
+struct Worker {
+    stream  : TcpStream,
+    reader  : BufReader<TcpStream>,
+    child   : Option<Child>,
+    alive   : bool
+}
+
+impl Worker {
+    fn new(stream: TcpStream, child: Option<Child>) -> io::Result<Worker> {
+        // the read timeout is raised to the shard timeout once the worker is set up
+        stream.set_read_timeout(Some(SETUP_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(Worker {
+            reader  : BufReader::new(stream.try_clone()?),
+            stream  : stream,
+            child   : child,
+            alive   : true
+        })
+    }
+}
+
+// Splits CTR batches into shards of consecutive blocks and evaluates them on
+// worker processes. A shard whose worker dies (or does not answer within the
+// timeout) is put back in the queue and picked up by one of the remaining
+// workers. A shard that a worker rejects fails the batch.
+pub struct ShardCoordinator {
+    workers     : Vec<Worker>,
+    shard_size  : usize,
+    parameters  : BooleanParameters
+}
+
+impl ShardCoordinator {
+
+    // connects to already running workers and ships the keys to each of them
+    pub fn connect(addrs: &[SocketAddr], server_key: &ServerKey, aes_key: &[AesByte; 176], parameters: BooleanParameters, shard_size: usize) -> Result<ShardCoordinator, FhaesError> {
+        let streams = addrs.iter().map(|addr| TcpStream::connect(addr)).collect::<io::Result<Vec<TcpStream>>>()?;
+        let workers = streams.into_iter().map(|stream| Worker::new(stream, None)).collect::<io::Result<Vec<Worker>>>()?;
+        ShardCoordinator::setup(workers, server_key, aes_key, parameters, shard_size)
+    }
+
+    // spawns `num_workers` local worker processes (see src/bin/fhaes_worker.rs),
+    // each one reports the address it listens on as the first line of its stdout
+    pub fn spawn_local(worker_exe: &Path, num_workers: usize, server_key: &ServerKey, aes_key: &[AesByte; 176], parameters: BooleanParameters, shard_size: usize) -> Result<ShardCoordinator, FhaesError> {
+        let mut workers = Vec::new();
+        for _ in 0..num_workers {
+            let mut child = Command::new(worker_exe)
+                .args(["--listen", "127.0.0.1:0"])
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let mut line = String::new();
+            BufReader::new(child.stdout.take().unwrap()).read_line(&mut line)?;
+            let addr: SocketAddr = line.trim().parse().map_err(|_| invalid_data("worker did not report its address"))?;
+            workers.push(Worker::new(TcpStream::connect(addr)?, Some(child))?);
+        }
+        ShardCoordinator::setup(workers, server_key, aes_key, parameters, shard_size)
+    }
+
+    fn setup(mut workers: Vec<Worker>, server_key: &ServerKey, aes_key: &[AesByte; 176], parameters: BooleanParameters, shard_size: usize) -> Result<ShardCoordinator, FhaesError> {
+        // serialized once, the same bytes are sent to every worker
+        let setup = bincode::serialize(&ShardSetupRef::Setup { server_key: server_key, aes_key: aes_key, parameters: parameters })?;
+        for worker in workers.iter_mut() {
+            let mut writer = BufWriter::new(&worker.stream);
+            writer.write_all(&setup)?;
+            writer.flush()?;
+            drop(writer);
+            match receive(&mut worker.reader)? {
+                ShardResponse::Ready { parameters: worker_parameters } => check_parameter_set(&parameters, &worker_parameters)?,
+                ShardResponse::Error(message) => return Err(FhaesError::Worker { message: message }),
+                _ => return Err(invalid_data("unexpected response to Setup"))
+            }
+            worker.stream.set_read_timeout(Some(DEFAULT_SHARD_TIMEOUT))?;
+        }
+        Ok(ShardCoordinator {
+            workers: workers,
+            shard_size: shard_size.max(1),
+            parameters: parameters
+        })
+    }
+
+    //set how long a worker may take for one shard before it is treated as dead
+    //and its shard is retried elsewhere, DEFAULT_SHARD_TIMEOUT by default
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), FhaesError> {
+        for worker in self.workers.iter().filter(|x| x.alive) {
+            worker.stream.set_read_timeout(Some(timeout))?;
+        }
+        Ok(())
+    }
+
+    // number of workers that have not failed so far
+    pub fn num_workers(&self) -> usize {
+        self.workers.iter().filter(|x| x.alive).count()
+    }
+
+    // same result as AesBoolean::generate_keystream + apply_keystream on the whole batch,
+    // the blocks and the iv are checked here before any of them is sent
+    pub fn encrypt_ctr_mode_fhe(&mut self, blocks: Vec<Vec<AesByte>>, iv: Vec<AesByte>) -> Result<Vec<Vec<AesByte>>, FhaesError> {
+        check_counter(blocks.len())?;
+        check_block(&self.parameters, "iv bytes", &iv)?;
+        for block in blocks.iter() {
+            check_block(&self.parameters, "bytes per block", block)?;
+        }
+        let shards: Vec<Vec<Vec<AesByte>>> = blocks.chunks(self.shard_size).map(|x| x.to_vec()).collect();
+        let num_shards = shards.len();
+        let results: Mutex<Vec<Option<Vec<Vec<AesByte>>>>> = Mutex::new(vec![None; num_shards]);
+        let failure: Mutex<Option<FhaesError>> = Mutex::new(None);
+        let shard_size = self.shard_size;
+
+        // a worker that fails puts its shard back and stops, the shards that are
+        // still missing after every live worker has drained the queue are retried
+        loop {
+            let missing: VecDeque<usize> = results.lock().unwrap().iter().enumerate()
+                .filter(|(_, x)| x.is_none())
+                .map(|(i, _)| i)
+                .collect();
+            if missing.is_empty() {
+                break;
+            }
+            if self.num_workers() == 0 {
+                return Err(FhaesError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "all workers died before the batch was done")));
+            }
+
+            let queue = Mutex::new(missing);
+            thread::scope(|scope| {
+                for worker in self.workers.iter_mut().filter(|x| x.alive) {
+                    let (queue, results, failure, shards, iv) = (&queue, &results, &failure, &shards, &iv);
+                    scope.spawn(move || {
+                        // the first failure is kept and the other workers stop after their current shard
+                        let fail = |e: FhaesError| {
+                            queue.lock().unwrap().clear();
+                            let mut failure = failure.lock().unwrap();
+                            if failure.is_none() {
+                                *failure = Some(e);
+                            }
+                        };
+                        loop {
+                            let shard_id = match queue.lock().unwrap().pop_front() {
+                                Some(shard_id) => shard_id,
+                                None => return
+                            };
+                            let request = ShardRequest::Encrypt {
+                                shard_id: shard_id,
+                                iv: iv.clone(),
+                                first_block: shard_id*shard_size,
+                                blocks: shards[shard_id].clone()
+                            };
+                            let response = send(&worker.stream, &request).and_then(|_| receive(&mut worker.reader));
+                            match response {
+                                Ok(ShardResponse::Encrypted { shard_id: returned_id, blocks }) if returned_id == shard_id => {
+                                    results.lock().unwrap()[shard_id] = Some(blocks);
+                                },
+                                // the worker rejected the shard, any other worker would as well
+                                Ok(ShardResponse::Error(message)) => {
+                                    fail(FhaesError::Worker { message: message });
+                                    return
+                                },
+                                // another shard or message, the worker is out of sync
+                                Ok(_) => {
+                                    worker.alive = false;
+                                    fail(invalid_data("worker answered with another shard than it was sent"));
+                                    return
+                                },
+                                // the worker died or timed out, retry the shard elsewhere
+                                Err(_) => {
+                                    worker.alive = false;
+                                    queue.lock().unwrap().push_back(shard_id);
+                                    return
+                                }
+                            }
+                        }
+                    });
+                }
+            });
+            if let Some(e) = failure.lock().unwrap().take() {
+                return Err(e);
+            }
+        }
+
+        let encrypted = results.into_inner().unwrap().into_iter().flatten().flatten().collect();
+        Ok(encrypted)
+    }
+}
+
+impl Drop for ShardCoordinator {
+    fn drop(&mut self) {
+        for worker in self.workers.iter_mut() {
+            let _ = send(&worker.stream, &ShardRequest::Shutdown);
+            if let Some(child) = worker.child.as_mut() {
+                if !worker.alive {
+                    let _ = child.kill();
+                }
+                let _ = child.wait();
+            }
+        }
+    }
+}
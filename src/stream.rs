@@ -0,0 +1,117 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use crate::cipher::HomomorphicBlockCipher;
+use crate::error::{FhaesError, MAX_CTR_BLOCKS};
+
+// Streams of encrypted blocks are the bincode encodings of the blocks one
+// after the other, without a header, so a stream can be written before its
+// length is known and read until the end of the input.
+
+// The input is untrusted, so a block whose encoding claims more than
+// `max_block_bytes` (e.g. a forged length prefix) fails with
+// FhaesError::Deserialization before anything is allocated for it.
+pub struct BlockReader<R: Read> {
+    reader          : BufReader<R>,
+    max_block_bytes : u64,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(input: R, max_block_bytes: u64) -> BlockReader<R> {
+        BlockReader {
+            reader          : BufReader::new(input),
+            max_block_bytes : max_block_bytes,
+        }
+    }
+
+    // up to `max_blocks` blocks, fewer only at the end of the input
+    pub fn read_batch<T: DeserializeOwned>(&mut self, max_blocks: usize) -> Result<Vec<T>, FhaesError> {
+        // the encoding of bincode::serialize_into, with the limit applying to each block
+        let options = bincode::options().with_fixint_encoding().allow_trailing_bytes().with_limit(self.max_block_bytes);
+        let mut results = Vec::new();
+        while results.len() < max_blocks && !self.reader.fill_buf()?.is_empty() {
+            results.push(options.deserialize_from(&mut self.reader)?);
+        }
+        Ok(results)
+    }
+}
+
+pub struct BlockWriter<W: Write> {
+    writer          : BufWriter<W>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(output: W) -> BlockWriter<W> {
+        BlockWriter {
+            writer          : BufWriter::new(output),
+        }
+    }
+
+    pub fn write_batch<T: Serialize>(&mut self, blocks: &[T]) -> Result<(), FhaesError> {
+        for block in blocks {
+            bincode::serialize_into(&mut self.writer, block)?;
+        }
+        Ok(())
+    }
+
+    // flushes and hands back the output
+    pub fn finish(self) -> Result<W, FhaesError> {
+        self.writer.into_inner().map_err(|e| FhaesError::Io(e.into_error()))
+    }
+}
+
+// Reads the blocks of `input` in batches of `batch_size`, passes every batch to
+// `f` together with the index of its first block, and writes what it returns
+// to `output`. At most one batch is held in memory, and no block of the input
+// may take more than `max_block_bytes`. Returns the number of blocks.
+pub fn map_stream<T, U, R, W, F>(input: R, output: W, batch_size: usize, max_block_bytes: u64, mut f: F) -> Result<usize, FhaesError>
+where
+    T: DeserializeOwned,
+    U: Serialize,
+    R: Read,
+    W: Write,
+    F: FnMut(usize, Vec<T>) -> Result<Vec<U>, FhaesError>,
+{
+    let mut reader = BlockReader::new(input, max_block_bytes);
+    let mut writer = BlockWriter::new(output);
+    let mut num_blocks = 0;
+    loop {
+        let batch: Vec<T> = reader.read_batch(usize::max(batch_size, 1))?;
+        if batch.is_empty() {
+            break;
+        }
+        let batch_len = batch.len();
+        writer.write_batch(&f(num_blocks, batch)?)?;
+        num_blocks += batch_len;
+    }
+    writer.finish()?;
+    Ok(num_blocks)
+}
+
+// CTR over a stream of encrypted blocks of any backend, each batch only
+// computes the keystream of its own counters. The counter covers MAX_CTR_BLOCKS
+// blocks, so every MAX_CTR_BLOCKS blocks the stream moves on to the next segment
+// iv (cipher::segment_bytes). The first MAX_CTR_BLOCKS blocks match encrypt_ctr.
+pub fn encrypt_ctr_stream<C, R, W>(cipher: &C, iv: &C::Block, input: R, output: W, batch_size: usize, max_block_bytes: u64) -> Result<usize, FhaesError>
+where
+    C: HomomorphicBlockCipher,
+    C::Block: Serialize + DeserializeOwned,
+    R: Read,
+    W: Write,
+{
+    map_stream(input, output, batch_size, max_block_bytes, |first_block, blocks: Vec<C::Block>| {
+        let end = first_block + blocks.len();
+        let mut keystream = Vec::with_capacity(blocks.len());
+        let mut start = first_block;
+        // a batch can straddle segments
+        while start < end {
+            let segment_start = start - start % MAX_CTR_BLOCKS;
+            let segment_end = usize::min(end, segment_start + MAX_CTR_BLOCKS);
+            let segment = u32::try_from(start / MAX_CTR_BLOCKS).map_err(|_| FhaesError::CounterOverflow { blocks: end })?;
+            let segment_iv = if segment == 0 { iv.clone() } else { cipher.segment_iv(iv, segment) };
+            keystream.extend(cipher.generate_keystream(&segment_iv, start - segment_start..segment_end - segment_start)?);
+            start = segment_end;
+        }
+        cipher.apply_keystream(&blocks, &keystream)
+    })
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::cipher::{counter_bytes, AesRounds};
+use crate::error::{check_counter, FhaesError};
+use crate::execution::{ExecutionConfig, Stage};
+use crate::fhaes_boolean::{check_block, AesBoolean, AesByte};
+
+// Refers to a tenant's key in a KeyRegistry, handles are never reused
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyHandle(u64);
+
+// One tenant's CTR request in a batch
+pub struct CtrRequest {
+    pub key         : KeyHandle,
+    pub iv          : Vec<AesByte>,
+    pub blocks      : Vec<Vec<AesByte>>,
+}
+
+// The encrypted round keys of many tenants. Each entry is a whole AesBoolean,
+// so tenants can use their own server key and parameters as well as their own
+// key schedule. The batch calls spread the blocks of all tenants over one
+// scheduler run, instead of encrypting one tenant at a time.
+pub struct KeyRegistry {
+    ciphers         : HashMap<KeyHandle, AesBoolean>,
+    next_handle     : u64,
+    execution       : ExecutionConfig,
+}
+
+impl KeyRegistry {
+
+    // initialise
+    pub fn new() -> KeyRegistry {
+        KeyRegistry {
+            ciphers         : HashMap::new(),
+            next_handle     : 0,
+            execution       : ExecutionConfig::default(),
+        }
+    }
+
+    // thread pool and the threshold of the blocks stage across tenants, the
+    // stages inside a block follow the ExecutionConfig of each AesBoolean
+    pub fn with_execution_config(mut self, execution: ExecutionConfig) -> KeyRegistry {
+        self.execution = execution;
+        self
+    }
+
+    // e.g. an AesBoolean from AesBooleanBuilder with the tenant's uploaded key schedule
+    pub fn register(&mut self, cipher: AesBoolean) -> KeyHandle {
+        let handle = KeyHandle(self.next_handle);
+        self.next_handle += 1;
+        self.ciphers.insert(handle, cipher);
+        handle
+    }
+
+    pub fn remove(&mut self, handle: KeyHandle) -> Option<AesBoolean> {
+        self.ciphers.remove(&handle)
+    }
+
+    pub fn get(&self, handle: KeyHandle) -> Result<&AesBoolean, FhaesError> {
+        self.ciphers.get(&handle).ok_or(FhaesError::UnknownKey { handle: handle.0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ciphers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ciphers.is_empty()
+    }
+
+    // every block under the key it references, all handles are resolved before any work starts
+    pub fn encrypt_blocks_fhe(&self, blocks: &[(KeyHandle, Vec<AesByte>)]) -> Result<Vec<Vec<AesByte>>, FhaesError> {
+        let ciphers = blocks.iter().map(|(handle, _)| self.get(*handle)).collect::<Result<Vec<_>, _>>()?;
+        self.execution.install(|| {
+            let results = self.execution.map(Stage::Blocks, blocks.len(), |i| ciphers[i].encrypt_block(blocks[i].1.clone()));
+            results.into_iter().collect()
+        })
+    }
+
+    // CTR for many tenants at once. The prefix cache of every request is computed
+    // first, then all the counter blocks of all the requests are one flat work list.
+    pub fn encrypt_ctr_batch_fhe(&self, requests: &[CtrRequest]) -> Result<Vec<Vec<Vec<AesByte>>>, FhaesError> {
+        let ciphers = requests.iter().map(|x| self.get(x.key)).collect::<Result<Vec<_>, _>>()?;
+        for (request, cipher) in requests.iter().zip(ciphers.iter()) {
+            check_counter(request.blocks.len())?;
+            for block in request.blocks.iter() {
+                check_block(&cipher.parameters(), "bytes per block", block)?;
+            }
+            check_block(&cipher.parameters(), "iv bytes", &request.iv)?;
+        }
+        // announced once the whole batch is valid
+        let _runs: Vec<_> = requests.iter().zip(ciphers.iter())
+            .map(|(request, cipher)| cipher.monitor().add_work(9*request.blocks.len()))
+            .collect();
+        // (request, counter) of every block in the batch
+        let work: Vec<(usize, usize)> = requests.iter().enumerate()
+            .flat_map(|(r, request)| (0..request.blocks.len()).map(move |i| (r, i)))
+            .collect();
+        self.execution.install(|| {
+            let caches = self.execution.map(Stage::Blocks, requests.len(), |r| ciphers[r].ctr_prefix_cache(&requests[r].iv));
+            let caches = caches.into_iter().collect::<Result<Vec<_>, _>>()?;
+            let results = self.execution.map(Stage::Blocks, work.len(), |k| {
+                let (r, i) = work[k];
+                let [counter_2, counter_1] = counter_bytes(i).map(AesByte::from_clear);
+                let c_n = ciphers[r].encrypt_counter_block_cached(&caches[r], &counter_1, &counter_2);
+                c_n.map(|c_n| ciphers[r].xor_blocks(&requests[r].blocks[i], &c_n))
+            });
+            // back into one list of blocks per request
+            let mut results = results.into_iter();
+            let mut grouped = Vec::new();
+            for request in requests {
+                grouped.push(results.by_ref().take(request.blocks.len()).collect::<Result<Vec<_>, _>>()?);
+            }
+            Ok(grouped)
+        })
+    }
+}
+
+impl Default for KeyRegistry {
+    fn default() -> KeyRegistry {
+        KeyRegistry::new()
+    }
+}
@@ -2,15 +2,28 @@ use tfhe::boolean::prelude::*;
 use tfhe::FheUint8;
 use tfhe::prelude::*;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use zeroize::Zeroizing;
 
-use crate::fhaes_boolean::AesByte;
+use crate::cipher::counter_bytes;
+use crate::error::{check_length, FhaesError};
+use crate::execution::{ExecutionConfig, Stage};
+use crate::parameters::{check_parameter_set, check_parameters};
+use crate::reference::SBOX;
+use crate::fhaes_boolean::{AesBit, AesByte, CompressedAesByte};
+use crate::fhaes_shortint::AesNibble;
 
 pub fn get_trivial_block() -> [FheUint8; 16] {
     [();16].map(|_| FheUint8::encrypt_trivial(0u8))
 }
 
-pub fn rot_word(word: &[u8]) -> [u8; 4] {
-    let mut result = [0u8; 4];
+// key schedule words, wiped when the result is dropped
+pub fn rot_word(word: &[u8]) -> Zeroizing<[u8; 4]> {
+    let mut result = Zeroizing::new([0u8; 4]);
     for i in 0..4 {
         if i!=3 {
             result[i]=word[i+1];
@@ -21,36 +34,22 @@ pub fn rot_word(word: &[u8]) -> [u8; 4] {
     return result
 }
 
-pub fn sub_word(word: &[u8]) -> [u8; 4] {
-    let sbox = [
-        // 0     1    2      3     4    5     6     7      8    9     A      B    C     D     E     F
-        0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,  // 0
-        0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,  // 1
-        0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,  // 2
-        0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,  // 3
-        0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,  // 4
-        0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,  // 5
-        0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,  // 6
-        0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,  // 7
-        0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,  // 8
-        0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,  // 9
-        0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,  // A
-        0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,  // B
-        0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,  // C
-        0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,  // D
-        0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,  // E
-        0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16]; // F
-    let mut result = [0u8; 4];
+pub fn sub_word(word: &[u8]) -> Zeroizing<[u8; 4]> {
+    let mut result = Zeroizing::new([0u8; 4]);
     for i in 0..4 {
-        result[i] = sbox[usize::from(word[i])];
+        result[i] = SBOX[usize::from(word[i])];
     }
     return result;
 }
 
-fn key_expand_core(word: &[u8]) -> [u8; 4] {
+// the AES SBox of a single byte
+pub fn sub_byte(byte: u8) -> u8 {
+    SBOX[usize::from(byte)]
+}
+
+fn key_expand_core(word: &[u8]) -> Zeroizing<[u8; 4]> {
     let rot_result = rot_word(word);
-    let sub_result = sub_word(&rot_result);
-    return sub_result
+    return sub_word(&rot_result[..])
 }
 
 pub fn xor_blocks(a: &[FheUint8; 16], b: &[FheUint8; 16]) -> [FheUint8; 16] {
@@ -61,8 +60,9 @@ pub fn xor_blocks(a: &[FheUint8; 16], b: &[FheUint8; 16]) -> [FheUint8; 16] {
     result
 }
 
-pub fn key_expansion(key: &[u8; 16]) -> [u8; 176] {
-    let mut expanded_key = [0u8; 176];
+// clear round keys, wiped when the result is dropped
+pub fn key_expansion(key: &[u8; 16]) -> Zeroizing<[u8; 176]> {
+    let mut expanded_key = Zeroizing::new([0u8; 176]);
     let rcon = [1u8, 2u8, 4u8, 8u8, 16u8, 32u8, 64u8, 128u8, 27u8, 54u8];
     for i in 0..44 {
         if i<4 {
@@ -88,55 +88,163 @@ pub fn key_expansion(key: &[u8; 16]) -> [u8; 176] {
     }
     return expanded_key
 }
-pub fn xor_aes_byte(server_key: &ServerKey, a: &AesByte, b: &AesByte) -> AesByte {
-    let b_bits = b.get_bits();
-    let result_bits = a.get_bits()
-        .into_par_iter()
-        .zip(0..8)
-        .map(|(x, y)| server_key.xor(&x, &b_bits[y]))
-        .collect();
-    AesByte::new(result_bits)
-}
-
-pub fn mix_mux_gate(server_key: &ServerKey, h: &Ciphertext, t: &Ciphertext, f: &Ciphertext) -> AesByte {
-    //0x1B = 00011011
-    let mut result_bits: Vec<Ciphertext> = Vec::new();
-    let c0 = f.clone();
-    let c1 = f.clone();
-    let c2 = f.clone();
-    let c3 = server_key.mux(h, t, f);
-    let c4 = server_key.mux(h, t, f);
-    let c5 = f.clone();
-    let c6 = server_key.mux(h, t, f);
-    let c7 = server_key.mux(h, t, f);
-    result_bits.push(c7);
-    result_bits.push(c6);
-    result_bits.push(c5);
-    result_bits.push(c4);
-    result_bits.push(c3);
-    result_bits.push(c2);
-    result_bits.push(c1);
-    result_bits.push(c0);
-    AesByte::new(result_bits)
-    
+// one step of the key schedule on encrypted bytes, turns the round key of
+// round_no-1 into the one of round_no (1..=10), costs 4 SBoxes
+pub fn key_expansion_round_fhe(server_key: &ServerKey, execution: &ExecutionConfig, previous: &Vec<AesByte>, round_no: usize) -> Vec<AesByte> {
+    let rcon = [1u8, 2u8, 4u8, 8u8, 16u8, 32u8, 64u8, 128u8, 27u8, 54u8];
+    let mut temp: Vec<AesByte> = execution.map(Stage::Bytes, 4, |k| previous[12+(k+1)%4].sbox(server_key));
+    temp[0] = xor_aes_byte(server_key, execution, &temp[0], &AesByte::from_clear(rcon[round_no-1]));
+
+    let mut result: Vec<AesByte> = Vec::new();
+    for i in 0..16 {
+        let word = if i<4 { temp[i].clone() } else { result[i-4].clone() };
+        result.push(xor_aes_byte(server_key, execution, &previous[i], &word));
+    }
+    result
 }
 
-pub fn byte_from_u8(client_key: &ClientKey, input: u8) -> AesByte {
-    let mut result_bytes = Vec::new();
-    for i in 0..8 {
-        let bit = ((input >> i) & 1) == 1;
-        result_bytes.push(client_key.encrypt(bit));
+pub fn xor_aes_byte(server_key: &ServerKey, execution: &ExecutionConfig, a: &AesByte, b: &AesByte) -> AesByte {
+    let (a_bits, b_bits) = (a.as_bits(), b.as_bits());
+    AesByte::new(execution.map(Stage::Bits, 8, |i| xor_bit(server_key, &a_bits[i], &b_bits[i])))
+}
+
+pub fn xor_bit(server_key: &ServerKey, a: &AesBit, b: &AesBit) -> AesBit {
+    match (a, b) {
+        (AesBit::Clear(x), AesBit::Clear(y)) => AesBit::Clear(x^y),
+        (AesBit::Clear(x), AesBit::Encrypted(y)) | (AesBit::Encrypted(y), AesBit::Clear(x)) => {
+            if *x { AesBit::Encrypted(server_key.not(y)) } else { AesBit::Encrypted(y.clone()) }
+        },
+        (AesBit::Encrypted(x), AesBit::Encrypted(y)) => AesBit::Encrypted(server_key.xor(x, y))
     }
-    return AesByte::new(result_bytes)
 }
 
-pub fn xor_aes_byte_blocks(server_key: &ServerKey, a: &Vec<AesByte>, b: &Vec<AesByte>) -> Vec<AesByte> {
-    let result = a.par_iter().zip(0..16).map(|(x, y)| xor_aes_byte(server_key, x,&b[y])).collect();
-    result
+pub fn and_bit(server_key: &ServerKey, a: &AesBit, b: &AesBit) -> AesBit {
+    match (a, b) {
+        (AesBit::Clear(x), AesBit::Clear(y)) => AesBit::Clear(*x && *y),
+        (AesBit::Clear(x), AesBit::Encrypted(y)) | (AesBit::Encrypted(y), AesBit::Clear(x)) => {
+            if *x { AesBit::Encrypted(y.clone()) } else { AesBit::Clear(false) }
+        },
+        (AesBit::Encrypted(x), AesBit::Encrypted(y)) => AesBit::Encrypted(server_key.and(x, y))
+    }
+}
+
+pub fn not_bit(server_key: &ServerKey, a: &AesBit) -> AesBit {
+    match a {
+        AesBit::Clear(x) => AesBit::Clear(!x),
+        AesBit::Encrypted(x) => AesBit::Encrypted(server_key.not(x))
+    }
+}
+
+pub fn xnor_bit(server_key: &ServerKey, a: &AesBit, b: &AesBit) -> AesBit {
+    match (a, b) {
+        (AesBit::Encrypted(x), AesBit::Encrypted(y)) => AesBit::Encrypted(server_key.xnor(x, y)),
+        _ => not_bit(server_key, &xor_bit(server_key, a, b))
+    }
+}
+
+pub fn mux_bit(server_key: &ServerKey, h: &AesBit, t: &AesBit, f: &AesBit) -> AesBit {
+    match (h, t, f) {
+        (AesBit::Clear(c), _, _) => if *c { t.clone() } else { f.clone() },
+        (AesBit::Encrypted(_), AesBit::Clear(x), AesBit::Clear(y)) => {
+            match (x, y) {
+                (true, false) => h.clone(),
+                (false, true) => not_bit(server_key, h),
+                _ => AesBit::Clear(*x)
+            }
+        },
+        (AesBit::Encrypted(c), _, _) => {
+            AesBit::Encrypted(server_key.mux(c, &t.to_ciphertext(server_key), &f.to_ciphertext(server_key)))
+        }
+    }
+}
+
+// the bits of input, least significant first, each through encrypt_bit
+fn encrypt_bits<T, F: Fn(bool) -> T>(input: u8, encrypt_bit: F) -> Vec<T> {
+    (0..8).map(|i| encrypt_bit(((input >> i) & 1) == 1)).collect()
+}
+
+pub fn byte_from_u8(client_key: &ClientKey, input: u8) -> AesByte {
+    AesByte::new(encrypt_bits(input, |bit| AesBit::Encrypted(client_key.encrypt(bit))))
+}
+
+// same as byte_from_u8 but about 100 times smaller to upload, see CompressedAesByte
+pub fn compressed_byte_from_u8(client_key: &ClientKey, input: u8) -> CompressedAesByte {
+    CompressedAesByte::new(encrypt_bits(input, |bit| client_key.encrypt_compressed(bit)))
+}
+
+pub fn decompress_bytes(bytes: &[CompressedAesByte]) -> Vec<AesByte> {
+    bytes.par_iter().map(|x| x.decompress()).collect()
+}
+
+pub fn xor_aes_byte_blocks(server_key: &ServerKey, execution: &ExecutionConfig, a: &Vec<AesByte>, b: &Vec<AesByte>) -> Vec<AesByte> {
+    execution.map(Stage::Bytes, 16, |i| xor_aes_byte(server_key, execution, &a[i], &b[i]))
+}
+
+// the bytes 15 (counters 1) and 14 (counters 2) of counter blocks 0..num_blocks,
+// see cipher::counter_bytes, each through encrypt_byte
+pub fn generate_counters_with<T: Send, F: Fn(u8) -> T + Sync + Send>(num_blocks: u16, encrypt_byte: F) -> (Vec<T>, Vec<T>) {
+    let (counter2, counter1) = (0..usize::from(num_blocks)).into_par_iter().map(|i| {
+        let [counter_2, counter_1] = counter_bytes(i);
+        (encrypt_byte(counter_2), encrypt_byte(counter_1))
+    }).unzip();
+    (counter1, counter2)
 }
 
 pub fn generate_counters(client_key: &ClientKey, num_blocks: u16) -> (Vec<AesByte>, Vec<AesByte>) {
-   let counter1 = (0..num_blocks).into_par_iter().map(|x| byte_from_u8(client_key,u8::try_from(x&0xf).unwrap())).collect();
-   let counter2 = (0..num_blocks).into_par_iter().map(|x| byte_from_u8(client_key,u8::try_from((x>>4)&0xf).unwrap())).collect();
-   (counter1, counter2)
+    generate_counters_with(num_blocks, |x| byte_from_u8(client_key, x))
+}
+
+pub fn generate_compressed_counters(client_key: &ClientKey, num_blocks: u16) -> (Vec<CompressedAesByte>, Vec<CompressedAesByte>) {
+    generate_counters_with(num_blocks, |x| compressed_byte_from_u8(client_key, x))
+}
+
+// counters for a public IV, these never need to be encrypted
+pub fn generate_clear_counters(num_blocks: u16) -> (Vec<AesByte>, Vec<AesByte>) {
+    generate_counters_with(num_blocks, AesByte::from_clear)
+}
+
+
+pub fn nibbles_from_u8(client_key: &tfhe::shortint::ClientKey, input: u8) -> AesNibble {
+    let hi = client_key.encrypt(u64::from(input >> 4));
+    let lo = client_key.encrypt(u64::from(input & 0xf));
+    AesNibble::new(hi, lo)
 }
+
+// written before the blocks of a stored keystream
+#[derive(Serialize, Deserialize)]
+struct KeystreamHeader {
+    parameters      : BooleanParameters,
+    first_block     : usize,
+    num_blocks      : usize
+}
+
+// persists a keystream of AesBoolean::generate_keystream, `range` being the
+// counter blocks it was generated for
+pub fn save_keystream(path: &Path, parameters: &BooleanParameters, range: Range<usize>, keystream: &[Vec<AesByte>]) -> Result<(), FhaesError> {
+    check_length("keystream blocks", range.len(), keystream.len())?;
+    let header = KeystreamHeader {
+        parameters      : *parameters,
+        first_block     : range.start,
+        num_blocks      : keystream.len()
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+    bincode::serialize_into(&mut writer, &header)?;
+    bincode::serialize_into(&mut writer, keystream)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// the counter range and blocks of a keystream stored with save_keystream, fails
+// with ParameterMismatch if it was generated under another parameter set
+pub fn load_keystream(path: &Path, parameters: &BooleanParameters) -> Result<(Range<usize>, Vec<Vec<AesByte>>), FhaesError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let header: KeystreamHeader = bincode::deserialize_from(&mut reader)?;
+    check_parameter_set(parameters, &header.parameters)?;
+    let keystream: Vec<Vec<AesByte>> = bincode::deserialize_from(&mut reader)?;
+    check_length("keystream blocks", header.num_blocks, keystream.len())?;
+    for block in keystream.iter() {
+        check_length("bytes per block", 16, block.len())?;
+        check_parameters(parameters, block)?;
+    }
+    Ok((header.first_block..header.first_block + header.num_blocks, keystream))
+}
\ No newline at end of file
@@ -0,0 +1,62 @@
+// Runs the sharded CTR mode on real fhaes_worker processes and kills one of
+// them while it is evaluating a shard.
+use fhaes::fhaes_boolean::AesByte;
+use fhaes::reference::ReferenceAes;
+use fhaes::shard::ShardCoordinator;
+use fhaes::utils::{byte_from_u8, key_expansion};
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread;
+use tfhe::boolean::gen_keys;
+use tfhe::boolean::prelude::DEFAULT_PARAMETERS;
+
+// starts the worker binary built by cargo and reads the address it listens on,
+// the rest of its stdout announces the shards it starts
+fn spawn_worker() -> (Child, SocketAddr, BufReader<ChildStdout>) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fhaes_worker"))
+        .args(["--listen", "127.0.0.1:0", "--announce-shards"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    (child, line.trim().parse().unwrap(), stdout)
+}
+
+#[test]
+fn killed_worker_mid_run() {
+    let key: [u8; 16] = rand::random();
+    let iv: [u8; 16] = rand::random();
+    let blocks: Vec<[u8; 16]> = (0..4).map(|_| rand::random()).collect();
+    let expanded_key = key_expansion(&key);
+    let (client_key, server_key) = gen_keys();
+    let fhe_key_aes = expanded_key.each_ref().map(|x| byte_from_u8(&client_key, *x));
+
+    let (mut victim, victim_addr, mut victim_stdout) = spawn_worker();
+    let (mut survivor, survivor_addr, _survivor_stdout) = spawn_worker();
+    let mut coordinator = ShardCoordinator::connect(&[victim_addr, survivor_addr], &server_key, &fhe_key_aes, DEFAULT_PARAMETERS, 1).unwrap();
+
+    // the victim is killed as soon as it announces its first shard, a boolean
+    // AES block takes seconds so it is still in the middle of it
+    let killer = thread::spawn(move || {
+        let mut line = String::new();
+        victim_stdout.read_line(&mut line).unwrap();
+        assert!(line.starts_with("shard "));
+        victim.kill().unwrap();
+        victim.wait().unwrap();
+    });
+    let iv_fhe: Vec<AesByte> = iv.iter().map(|x| byte_from_u8(&client_key, *x)).collect();
+    let blocks_fhe: Vec<Vec<AesByte>> = blocks.iter().map(|x| x.iter().map(|y| byte_from_u8(&client_key, *y)).collect()).collect();
+    let encrypted = coordinator.encrypt_ctr_mode_fhe(blocks_fhe, iv_fhe).unwrap();
+    killer.join().unwrap();
+
+    // every shard is there, in order, and the dead worker is no longer used
+    let decrypted: Vec<[u8; 16]> = encrypted.iter().map(|x| std::array::from_fn(|i| x[i].decrypt(&client_key))).collect();
+    assert_eq!(ReferenceAes::from_key(&key).encrypt_ctr_mode(blocks, iv).unwrap(), decrypted);
+    assert_eq!(1, coordinator.num_workers());
+
+    drop(coordinator);
+    survivor.wait().unwrap();
+}